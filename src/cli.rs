@@ -0,0 +1,270 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use koala_transaction_engine::engine::{
+    DuplicatePolicy, WithdrawalDisputePolicy,
+};
+use koala_transaction_engine::rejection::ReportFormat;
+use koala_transaction_engine::{ErrorPolicy, DEFAULT_BATCH_SIZE};
+
+/// Processed everything without problems
+pub const EXIT_SUCCESS: u8 = 0;
+/// Processing failed with an engine, io or db error
+pub const EXIT_FAILURE: u8 = 1;
+/// Bad arguments or missing configuration
+pub const EXIT_USAGE: u8 = 2;
+/// Replay output differs, or the input has invalid rows
+pub const EXIT_MISMATCH: u8 = 3;
+
+/// Config file used when none is given
+pub const DEFAULT_CONFIG: &str = "proj-config.toml";
+
+/// Koala transaction engine
+///
+/// Every path can come from a flag, a `KOALA_<KEY>` environment
+/// variable (e.g. `KOALA_INPUT_FILE`) or the config file, in that
+/// order of precedence.
+#[derive(Debug, Parser)]
+#[clap(name = "koala", version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// Config file with the default paths [default: proj-config.toml]
+    #[clap(long, global = true, env = "KOALA_CONFIG")]
+    pub config: Option<String>,
+
+    /// Log levels, e.g. `info` or `warn,koala_transaction_engine::db=trace`
+    #[clap(long, global = true, env = "KOALA_LOG", default_value = "warn")]
+    pub log: String,
+
+    #[clap(long, global = true, value_enum, default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// Shorthand for `process --backend memory --input <INPUT> --output -`
+    pub input: Option<String>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Process an input file and write the account table
+    Process(ProcessArgs),
+    /// Print the account table left by a previous run
+    Report(ReportArgs),
+    /// Re-run an input file and compare with an expected account table
+    Replay(ReplayArgs),
+    /// Check every row of an input file without processing it
+    Validate(ValidateArgs),
+}
+
+/// How log lines are written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    /// One json object per line
+    Json,
+}
+
+/// Which engine implementation processes the transactions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Client records in a csv file, rewritten on every update
+    Csv,
+    /// Client records in a sqlite database
+    Db,
+    /// Client records in memory, written once at the end
+    Memory,
+}
+
+/// What happens when a transaction is refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OnError {
+    /// Stop at the first refused transaction
+    Abort,
+    /// Record the refused transaction and carry on
+    Skip,
+    /// Record it and hold back later transactions of the client
+    Quarantine,
+}
+
+impl From<OnError> for ErrorPolicy {
+    fn from(on_error: OnError) -> Self {
+        match on_error {
+            OnError::Abort => ErrorPolicy::Abort,
+            OnError::Skip => ErrorPolicy::Skip,
+            OnError::Quarantine => ErrorPolicy::Quarantine,
+        }
+    }
+}
+
+/// What happens to a transaction reusing a tx id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Duplicates {
+    /// Refuse every reuse of a tx id
+    Reject,
+    /// Ignore exact repeats, refuse the rest
+    Replay,
+}
+
+impl From<Duplicates> for DuplicatePolicy {
+    fn from(duplicates: Duplicates) -> Self {
+        match duplicates {
+            Duplicates::Reject => DuplicatePolicy::Reject,
+            Duplicates::Replay => DuplicatePolicy::Replay,
+        }
+    }
+}
+
+/// What happens when a withdrawal is disputed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WithdrawalDisputes {
+    /// Refuse the dispute, only deposits can be disputed
+    Reject,
+    /// Hold the withdrawn funds until resolved or charged back
+    ReverseAndHold,
+}
+
+impl From<WithdrawalDisputes> for WithdrawalDisputePolicy {
+    fn from(disputes: WithdrawalDisputes) -> Self {
+        match disputes {
+            WithdrawalDisputes::Reject => WithdrawalDisputePolicy::Reject,
+            WithdrawalDisputes::ReverseAndHold => {
+                WithdrawalDisputePolicy::ReverseAndHold
+            }
+        }
+    }
+}
+
+/// File format of the refused transaction report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RejectsFormat {
+    Csv,
+    /// One json object per line
+    Jsonl,
+}
+
+impl From<RejectsFormat> for ReportFormat {
+    fn from(format: RejectsFormat) -> Self {
+        match format {
+            RejectsFormat::Csv => ReportFormat::Csv,
+            RejectsFormat::Jsonl => ReportFormat::JsonLines,
+        }
+    }
+}
+
+/// How the summary of a run is printed to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SummaryFormat {
+    Table,
+    /// One json object
+    Json,
+}
+
+#[derive(Debug, Args)]
+pub struct ProcessArgs {
+    #[clap(long, value_enum, default_value = "csv")]
+    pub backend: Backend,
+
+    /// What to do with refused transactions
+    #[clap(long, value_enum, default_value = "skip")]
+    pub on_error: OnError,
+
+    /// What to do with reused tx ids
+    #[clap(long, value_enum, default_value = "reject")]
+    pub duplicates: Duplicates,
+
+    /// What to do with disputes of withdrawals
+    #[clap(long, value_enum, default_value = "reject")]
+    pub withdrawal_disputes: WithdrawalDisputes,
+
+    /// Report of refused transactions [config: rejects_file]
+    #[clap(long)]
+    pub rejects: Option<String>,
+
+    #[clap(long, value_enum, default_value = "csv")]
+    pub rejects_format: RejectsFormat,
+
+    /// Transactions to process [config: input_file]
+    #[clap(long)]
+    pub input: Option<String>,
+
+    /// Stream to read transactions from instead of the input file,
+    /// repeat it for several: a path, `-` for stdin, `tcp://host:port`
+    /// or `unix:path`. Each stream is applied in its own order.
+    #[clap(long = "source", conflicts_with = "input")]
+    pub sources: Vec<String>,
+
+    /// Stream of an operator, given like --source and read along
+    /// with the input. Freeze, unlock and adjustment transactions
+    /// are only accepted from these, repeat it for several.
+    #[clap(long = "operator")]
+    pub operators: Vec<String>,
+
+    /// Account table, `-` for stdout [config: output_file]
+    #[clap(long)]
+    pub output: Option<String>,
+
+    /// Scratch file for the csv backend [config: tmp_csv_file]
+    #[clap(long)]
+    pub tmp_file: Option<String>,
+
+    /// Database for the db backend [config: tmp_db_file]
+    #[clap(long)]
+    pub db_file: Option<String>,
+
+    /// Keep the balances and history already in the database and
+    /// add this input to them, instead of starting afresh
+    #[clap(long)]
+    pub persist: bool,
+
+    /// Carry on from where an interrupted run of the same input
+    /// stopped, implies --persist
+    #[clap(long)]
+    pub resume: bool,
+
+    /// Rows committed to the database together, a crash loses at
+    /// most one batch, which --resume then processes again
+    #[clap(long, default_value_t = DEFAULT_BATCH_SIZE)]
+    pub batch_size: usize,
+
+    /// Worker threads of the memory backend, each applying the
+    /// transactions of its share of the clients
+    #[clap(long, default_value_t = 1)]
+    pub threads: usize,
+
+    /// Print what the run did to stderr once it is done: rows of
+    /// each kind, refusals by reason, totals moved and throughput
+    #[clap(long, value_enum)]
+    pub summary: Option<SummaryFormat>,
+}
+
+#[derive(Debug, Args)]
+pub struct ReportArgs {
+    /// Where the previous run kept the client records
+    #[clap(long, value_enum, default_value = "csv")]
+    pub backend: Backend,
+
+    /// Account table of the csv backend [config: output_file]
+    #[clap(long)]
+    pub output: Option<String>,
+
+    /// Database of the db backend [config: tmp_db_file]
+    #[clap(long)]
+    pub db_file: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ReplayArgs {
+    /// Transactions to replay [config: input_file]
+    #[clap(long)]
+    pub input: Option<String>,
+
+    /// Account table to compare with [config: output_test_file]
+    #[clap(long)]
+    pub expected: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ValidateArgs {
+    /// Transactions to check [config: input_file]
+    #[clap(long)]
+    pub input: Option<String>,
+}
@@ -1,13 +1,36 @@
-use crate::engine::{ClientRecord, Engine, Transaction};
+use crate::engine::{
+    ClientRecord, DisputeState, Engine, EngineConfig, Transaction,
+};
 use crate::errors::KoalaError;
+use crate::mem::RecordStore;
+use crate::money::Money;
 use csv::{Reader, Writer};
+use std::collections::HashMap;
+use std::io::Write;
+use tracing::trace;
+
+/// Where the CSV processor keeps the client records
+enum Output {
+    /// Records live in the output file, which is rewritten
+    /// through the tmp file on every update
+    File {
+        tmp_file: String,
+        output_file: String,
+    },
+    /// Records are kept in memory and the final account
+    /// table is written once to the writer at the end
+    Stream {
+        records: RecordStore,
+        writer: Box<dyn Write>,
+    },
+}
 
 /// The main struct for CSV processor, it keeps track
 /// of trasactions, and the necessary files
 pub struct CSVProcessor {
-    txs: Vec<Transaction>,
-    tmp_file: String,
-    output_file: String,
+    txs: HashMap<String, Transaction>,
+    output: Output,
+    config: EngineConfig,
 }
 
 impl CSVProcessor {
@@ -17,142 +40,82 @@ impl CSVProcessor {
         output_file: String,
     ) -> Result<CSVProcessor, KoalaError> {
         Ok(CSVProcessor {
-            txs: Vec::new(),
-            tmp_file: tmp_file,
-            output_file: output_file,
+            txs: HashMap::new(),
+            config: EngineConfig::default(),
+            output: Output::File {
+                tmp_file,
+                output_file,
+            },
         })
     }
-}
-
-impl Engine for CSVProcessor {
-    /// Deposit transaction
-    fn deposit_tx(&mut self, tx: Transaction) -> Result<(), KoalaError> {
-        self.print_tx_header("deposit");
-        let client_id = tx.client;
-        let mut client_record = self.get_client_record(client_id)?;
-
-        if client_record.locked {
-            return Err(KoalaError::AccountLockedError);
-        }
-
-        let amount = match tx.amount {
-            Some(amt) => amt,
-            None => 0.0,
-        };
 
-        self.txs.push(tx);
-
-        if amount > 0.0 {
-            client_record.available += amount;
-            client_record.total = client_record.available + client_record.held;
-            self.update_record(&client_record)?;
-            return Ok(());
-        }
-
-        Err(KoalaError::PartnerError)
+    /// Returns a new CSV processor which keeps client records in
+    /// memory and writes the account table to the writer once
+    /// processing has finished, e.g. to stdout
+    pub fn streaming(
+        writer: impl Write + 'static,
+    ) -> Result<CSVProcessor, KoalaError> {
+        Ok(CSVProcessor {
+            txs: HashMap::new(),
+            config: EngineConfig::default(),
+            output: Output::Stream {
+                records: RecordStore::default(),
+                writer: Box::new(writer),
+            },
+        })
     }
+}
 
-    /// Withdrawal transaction
-    fn withdrawal_tx(&mut self, tx: Transaction) -> Result<(), KoalaError> {
-        self.print_tx_header("withdrawal");
-        let client_id = tx.client;
-        let mut client_record = self.get_client_record(client_id)?;
-
-        if client_record.locked {
-            return Err(KoalaError::AccountLockedError);
-        }
-
-        let amount = match tx.amount {
-            Some(amt) => amt,
-            None => 0.0,
-        };
-
-        self.txs.push(tx);
-
-        if amount <= client_record.available {
-            client_record.available -= amount;
-            client_record.total = client_record.available + client_record.held;
-            self.update_record(&client_record)?;
-            return Ok(());
-        }
-
-        Err(KoalaError::BalanceError)
+impl CSVProcessor {
+    /// Replaces the default engine settings
+    pub fn with_config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self
     }
+}
 
-    /// Dispute transaction
-    fn dispute_tx(&self, tx: &Transaction) -> Result<(), KoalaError> {
-        self.print_tx_header("dispute");
-        let client_id = tx.client;
-        let mut client_record = self.get_client_record(client_id)?;
-
-        if client_record.locked {
-            return Err(KoalaError::AccountLockedError);
-        }
-
-        if let Some(transaction) = self.find_original_transaction(&tx.tx) {
-            let amount = transaction.amount.unwrap_or_default();
-            client_record.available -= amount;
-            client_record.held += amount;
-            client_record.total = client_record.available + client_record.held;
-            self.update_record(&client_record)?;
-            return Ok(());
-        }
+/// Returns every client record of an account table
+/// written by a previous run, sorted by client id
+pub fn read_client_records(
+    output_file: &str,
+) -> Result<Vec<ClientRecord>, KoalaError> {
+    let mut rdr = Reader::from_path(output_file)?;
+    let mut records = rdr
+        .deserialize()
+        .collect::<Result<Vec<ClientRecord>, csv::Error>>()?;
+    records.sort_by_key(|record| record.id);
+    Ok(records)
+}
 
-        Err(KoalaError::PartnerError)
+impl Engine for CSVProcessor {
+    fn config(&self) -> &EngineConfig {
+        &self.config
     }
 
-    /// Resolve transaction
-    fn resolve_tx(&self, tx: &Transaction) -> Result<(), KoalaError> {
-        self.print_tx_header("resolve");
-        let client_id = tx.client;
-        let mut client_record = self.get_client_record(client_id)?;
-
-        if client_record.locked {
-            return Err(KoalaError::AccountLockedError);
-        }
-
-        if let Some(transaction) = self.find_original_transaction(&tx.tx) {
-            let amount = transaction.amount.unwrap_or_default();
-            client_record.held -= amount;
-            client_record.available += amount;
-            client_record.total = client_record.available + client_record.held;
-            self.update_record(&client_record)?;
-            return Ok(());
-        }
-
-        Err(KoalaError::PartnerError)
+    /// Keeps the transaction for later disputes
+    fn store_transaction(&mut self, tx: Transaction) -> Result<(), KoalaError> {
+        self.txs.entry(tx.tx.clone()).or_insert(tx);
+        Ok(())
     }
 
-    /// Chargeback transaction
-    fn chargeback_tx(&self, tx: &Transaction) -> Result<(), KoalaError> {
-        self.print_tx_header("chargeback");
-        let client_id = tx.client;
-        let mut client_record = self.get_client_record(client_id)?;
-
-        if client_record.locked {
-            return Err(KoalaError::AccountLockedError);
-        }
-
-        if let Some(transaction) = self.find_original_transaction(&tx.tx) {
-            let amount = transaction.amount.unwrap_or_default();
-            client_record.held -= amount;
-            client_record.locked = true;
-            client_record.total = client_record.available + client_record.held;
-            self.update_record(&client_record)?;
-            return Ok(());
-        }
-
-        Err(KoalaError::PartnerError)
+    /// Returns a transaction, given a transaction id
+    fn find_original_transaction(
+        &self,
+        tx_id: &str,
+    ) -> Result<Option<Transaction>, KoalaError> {
+        Ok(self.txs.get(tx_id).cloned())
     }
 
-    /// Returns a transaction, given a transaction id
-    fn find_original_transaction(&self, tx_id: &str) -> Option<&Transaction> {
-        for transaction in &self.txs {
-            if transaction.tx == tx_id {
-                return Some(transaction);
-            }
+    /// Updates the dispute state of a transaction
+    fn set_dispute_state(
+        &mut self,
+        tx_id: &str,
+        state: DisputeState,
+    ) -> Result<(), KoalaError> {
+        if let Some(transaction) = self.txs.get_mut(tx_id) {
+            transaction.state = state;
         }
-        None
+        Ok(())
     }
 
     /// Returns a client record, given a client id
@@ -160,33 +123,69 @@ impl Engine for CSVProcessor {
         &self,
         client_id: u16,
     ) -> Result<ClientRecord, KoalaError> {
-        let mut rdr = Reader::from_path(self.output_file.as_str())?;
-
-        for result in rdr.deserialize() {
-            let record: ClientRecord = result?;
-            if record.id == client_id {
-                return Ok(record);
+        match &self.output {
+            Output::File { output_file, .. } => {
+                let mut rdr = Reader::from_path(output_file.as_str())?;
+
+                for result in rdr.deserialize() {
+                    let record: ClientRecord = result?;
+                    if record.id == client_id {
+                        return Ok(record);
+                    }
+                }
+            }
+            Output::Stream { records, .. } => {
+                return Ok(records.get(client_id));
             }
         }
 
         let client_record = ClientRecord {
             id: client_id,
-            available: 0.0,
-            held: 0.0,
-            total: 0.0,
+            available: Money::ZERO,
+            held: Money::ZERO,
+            total: Money::ZERO,
             locked: false,
         };
 
         Ok(client_record)
     }
 
+    /// Whether the client has a record
+    fn client_exists(&self, client_id: u16) -> Result<bool, KoalaError> {
+        match &self.output {
+            Output::File { output_file, .. } => {
+                let mut rdr = Reader::from_path(output_file.as_str())?;
+
+                for result in rdr.deserialize() {
+                    let record: ClientRecord = result?;
+                    if record.id == client_id {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Output::Stream { records, .. } => Ok(records.contains(client_id)),
+        }
+    }
+
     /// Updates the client record, with the new record
-    fn update_record(&self, cr: &ClientRecord) -> Result<(), KoalaError> {
-        println!("Updating - {:?}", cr);
+    fn update_record(&mut self, cr: &ClientRecord) -> Result<(), KoalaError> {
+        trace!(?cr, "updating record");
+
+        let (tmp_file, output_file) = match &mut self.output {
+            Output::File {
+                tmp_file,
+                output_file,
+            } => (tmp_file.as_str(), output_file.as_str()),
+            Output::Stream { records, .. } => {
+                records.update(cr);
+                return Ok(());
+            }
+        };
 
         let mut existing = false;
-        let mut rdr = Reader::from_path(self.output_file.as_str())?;
-        let mut wtr = Writer::from_path(self.tmp_file.as_str())?;
+        let mut rdr = Reader::from_path(output_file)?;
+        let mut wtr = Writer::from_path(tmp_file)?;
 
         for result in rdr.deserialize() {
             let record: ClientRecord = result?;
@@ -206,8 +205,17 @@ impl Engine for CSVProcessor {
         wtr.flush()?;
         drop(wtr);
 
-        std::fs::remove_file(self.output_file.as_str())?;
-        std::fs::rename(self.tmp_file.as_str(), self.output_file.as_str())?;
+        std::fs::remove_file(output_file)?;
+        std::fs::rename(tmp_file, output_file)?;
+
+        Ok(())
+    }
+
+    /// Writes the account table, when records are kept in memory
+    fn finish(&mut self) -> Result<(), KoalaError> {
+        if let Output::Stream { records, writer } = &mut self.output {
+            records.write_table(writer)?;
+        }
 
         Ok(())
     }
@@ -216,6 +224,7 @@ impl Engine for CSVProcessor {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::engine::TransactionKind;
     use rand::{thread_rng, Rng};
 
     const TMP_FILE: &str = "data/tmp/tmp_test.csv";
@@ -224,10 +233,10 @@ mod tests {
     #[test]
     fn test_deposit_tx() {
         let (mut processor, tmp_file, tmp_out_file) = prep_test();
-        let tx_type = String::from("deposit");
+        let tx_type = TransactionKind::Deposit;
         let client_id = 1;
         let tx_id = String::from("1");
-        let amount = 10.0;
+        let amount: Money = "10.0".parse().unwrap();
 
         let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
         processor.deposit_tx(tx).unwrap();
@@ -237,7 +246,7 @@ mod tests {
 
         assert_eq!(cr.available, amount);
         assert_eq!(cr.total, amount);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.held, Money::ZERO);
 
         cleanup(tmp_file, tmp_out_file);
     }
@@ -247,10 +256,10 @@ mod tests {
         let (mut processor, tmp_file, tmp_out_file) = prep_test();
 
         // We must do a deposit first
-        let tx_type = String::from("deposit");
+        let tx_type = TransactionKind::Deposit;
         let client_id = 1;
         let tx_id = String::from("1");
-        let amount = 10.0;
+        let amount: Money = "10.0".parse().unwrap();
         let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
         processor.deposit_tx(tx).unwrap();
 
@@ -258,19 +267,19 @@ mod tests {
         let cr = processor.get_client_record(client_id).unwrap();
         assert_eq!(cr.available, amount);
         assert_eq!(cr.total, amount);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.held, Money::ZERO);
 
         // Withdrawal now
-        let tx_type = String::from("withdrawal");
+        let tx_type = TransactionKind::Withdrawal;
         let tx_id = String::from("2");
         let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
         processor.withdrawal_tx(tx).unwrap();
 
         // Verify the result
         let cr = processor.get_client_record(client_id).unwrap();
-        assert_eq!(cr.available, 0.0);
-        assert_eq!(cr.total, 0.0);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.available, Money::ZERO);
+        assert_eq!(cr.total, Money::ZERO);
+        assert_eq!(cr.held, Money::ZERO);
 
         cleanup(tmp_file, tmp_out_file);
     }
@@ -280,10 +289,10 @@ mod tests {
         let (mut processor, tmp_file, tmp_out_file) = prep_test();
 
         // We must do a deposit first
-        let tx_type = String::from("deposit");
+        let tx_type = TransactionKind::Deposit;
         let client_id = 1;
         let tx_id = String::from("1");
-        let amount = 10.0;
+        let amount: Money = "10.0".parse().unwrap();
         let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
         processor.deposit_tx(tx).unwrap();
 
@@ -291,17 +300,17 @@ mod tests {
         let cr = processor.get_client_record(client_id).unwrap();
         assert_eq!(cr.available, amount);
         assert_eq!(cr.total, amount);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.held, Money::ZERO);
 
         // Dispute now
-        let tx_type = String::from("dispute");
+        let tx_type = TransactionKind::Dispute;
         let tx_id = String::from("1");
         let tx = Transaction::new(tx_type, client_id, tx_id, None);
         processor.dispute_tx(&tx).unwrap();
 
         // Verify the result
         let cr = processor.get_client_record(client_id).unwrap();
-        assert_eq!(cr.available, 0.0);
+        assert_eq!(cr.available, Money::ZERO);
         assert_eq!(cr.total, amount);
         assert_eq!(cr.held, amount);
 
@@ -313,10 +322,10 @@ mod tests {
         let (mut processor, tmp_file, tmp_out_file) = prep_test();
 
         // We must do a deposit first
-        let tx_type = String::from("deposit");
+        let tx_type = TransactionKind::Deposit;
         let client_id = 1;
         let tx_id = String::from("1");
-        let amount = 10.0;
+        let amount: Money = "10.0".parse().unwrap();
         let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
         processor.deposit_tx(tx).unwrap();
 
@@ -324,22 +333,22 @@ mod tests {
         let cr = processor.get_client_record(client_id).unwrap();
         assert_eq!(cr.available, amount);
         assert_eq!(cr.total, amount);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.held, Money::ZERO);
 
         // Dispute now
-        let tx_type = String::from("dispute");
+        let tx_type = TransactionKind::Dispute;
         let tx_id = String::from("1");
         let tx = Transaction::new(tx_type, client_id, tx_id, None);
         processor.dispute_tx(&tx).unwrap();
 
         // Verify the result
         let cr = processor.get_client_record(client_id).unwrap();
-        assert_eq!(cr.available, 0.0);
+        assert_eq!(cr.available, Money::ZERO);
         assert_eq!(cr.total, amount);
         assert_eq!(cr.held, amount);
 
         // Resolve now
-        let tx_type = String::from("resolve");
+        let tx_type = TransactionKind::Resolve;
         let tx_id = String::from("1");
         let tx = Transaction::new(tx_type, client_id, tx_id, None);
         processor.resolve_tx(&tx).unwrap();
@@ -348,7 +357,7 @@ mod tests {
         let cr = processor.get_client_record(client_id).unwrap();
         assert_eq!(cr.available, amount);
         assert_eq!(cr.total, amount);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.held, Money::ZERO);
 
         cleanup(tmp_file, tmp_out_file);
     }
@@ -358,10 +367,10 @@ mod tests {
         let (mut processor, tmp_file, tmp_out_file) = prep_test();
 
         // We must do a deposit first
-        let tx_type = String::from("deposit");
+        let tx_type = TransactionKind::Deposit;
         let client_id = 1;
         let tx_id = String::from("1");
-        let amount = 10.0;
+        let amount: Money = "10.0".parse().unwrap();
         let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
         processor.deposit_tx(tx).unwrap();
 
@@ -369,32 +378,67 @@ mod tests {
         let cr = processor.get_client_record(client_id).unwrap();
         assert_eq!(cr.available, amount);
         assert_eq!(cr.total, amount);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.held, Money::ZERO);
 
         // Dispute now
-        let tx_type = String::from("dispute");
+        let tx_type = TransactionKind::Dispute;
         let tx_id = String::from("1");
         let tx = Transaction::new(tx_type, client_id, tx_id, None);
         processor.dispute_tx(&tx).unwrap();
 
         // Verify the result
         let cr = processor.get_client_record(client_id).unwrap();
-        assert_eq!(cr.available, 0.0);
+        assert_eq!(cr.available, Money::ZERO);
         assert_eq!(cr.total, amount);
         assert_eq!(cr.held, amount);
 
         // Chargeback now
-        let tx_type = String::from("chargeback");
+        let tx_type = TransactionKind::Chargeback;
         let tx_id = String::from("1");
         let tx = Transaction::new(tx_type, client_id, tx_id, None);
         processor.chargeback_tx(&tx).unwrap();
 
         // Verify the result
         let cr = processor.get_client_record(client_id).unwrap();
-        assert_eq!(cr.available, 0.0);
-        assert_eq!(cr.total, 0.0);
-        assert_eq!(cr.held, 0.0);
-        assert_eq!(cr.locked, true);
+        assert_eq!(cr.available, Money::ZERO);
+        assert_eq!(cr.total, Money::ZERO);
+        assert_eq!(cr.held, Money::ZERO);
+        assert!(cr.locked);
+
+        cleanup(tmp_file, tmp_out_file);
+    }
+
+    #[test]
+    fn test_streaming_output() {
+        let (_, tmp_file, tmp_out_file) = prep_test();
+        let writer = std::fs::File::create(tmp_out_file.as_str()).unwrap();
+        let mut processor = CSVProcessor::streaming(writer).unwrap();
+
+        let amount: Money = "10.0".parse().unwrap();
+        for (client_id, tx_id) in [(2, "1"), (1, "2")] {
+            let tx_type = TransactionKind::Deposit;
+            let tx = Transaction::new(
+                tx_type,
+                client_id,
+                String::from(tx_id),
+                Some(amount),
+            );
+            processor.deposit_tx(tx).unwrap();
+        }
+
+        // Nothing is written until processing finishes
+        let output = std::fs::read_to_string(tmp_out_file.as_str()).unwrap();
+        assert!(output.is_empty());
+        assert_eq!(processor.get_client_record(2).unwrap().available, amount);
+
+        processor.finish().unwrap();
+        let output = std::fs::read_to_string(tmp_out_file.as_str()).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n\
+             1,10.0000,0.0000,10.0000,false\n\
+             2,10.0000,0.0000,10.0000,false\n"
+        );
 
         cleanup(tmp_file, tmp_out_file);
     }
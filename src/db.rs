@@ -1,39 +1,108 @@
 extern crate rusqlite;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use rusqlite::{Connection, OpenFlags, OptionalExtension, params};
 
-use crate::engine::{ClientRecord, Engine, Transaction};
+use crate::engine::{
+    Checkpoint, ClientRecord, DisputeState, Engine, EngineConfig,
+    Transaction, TransactionKind,
+};
 use crate::errors::KoalaError;
+use crate::money::Money;
+use std::collections::BTreeSet;
+use tracing::{info, trace};
 
 const TABLE_NAME: &str = "CLIENT_RECORDS";
-
-/// The main struct for DB processor, it keeps track
-/// of trasactions and the DB connection
+const TX_TABLE_NAME: &str = "TRANSACTIONS";
+const CHECKPOINT_TABLE_NAME: &str = "CHECKPOINT";
+
+/// Schema migrations, a database at version n has had the
+/// first n applied, the files numbered 1 to n. Only ever
+/// append to the list.
+const MIGRATIONS: [&str; 3] = [
+    include_str!("migrations/0001_initial.sql"),
+    include_str!("migrations/0002_transaction_history.sql"),
+    include_str!("migrations/0003_checkpoint_quarantine.sql"),
+];
+
+/// Converts the REAL balances of a database from before the schema
+/// was versioned, run along with the first migration when needed
+const LEGACY_REAL_BALANCES: &str = include_str!("migrations/legacy_real_balances.sql");
+
+/// The main struct for DB processor, it keeps the DB
+/// connection, transactions are kept in the DB too
 pub struct DBProcessor {
-    txs: Vec<Transaction>,
-    connection: Connection
+    connection: Connection,
+    config: EngineConfig,
+    /// Whether a batch transaction is open
+    in_batch: bool,
 }
 
 impl DBProcessor {
-    /// Returns a new DB Processor
+    /// Returns a new DB Processor, the database must be empty
     pub fn new(
         db_file: String,
     ) -> Result<DBProcessor, KoalaError> {
-        let connection = Connection::open_with_flags(
+        let mut connection = Connection::open_with_flags(
             &db_file,
             OpenFlags::SQLITE_OPEN_READ_WRITE,
         )?;
-        let query = format!("CREATE TABLE {} ( id INTEGER PRIMARY KEY, available REAL, held REAL, total REAL, locked BOOL )", TABLE_NAME);
-        connection.execute(&query, ())?;
+        let tables: u32 = connection.query_row("SELECT count(*) FROM sqlite_master", [], |row| row.get(0))?;
+        if tables > 0 {
+            return Err(KoalaError::SchemaError(format!("{} is not an empty database", db_file)));
+        }
+        set_pragmas(&connection)?;
+        migrate(&mut connection)?;
+
+        Ok(DBProcessor {
+            connection,
+            config: EngineConfig::default(),
+            in_batch: false,
+        })
+    }
+
+    /// Returns a DB Processor which carries on from the balances and
+    /// transaction history of an existing database, which is created
+    /// if there is none yet and upgraded if it is older
+    pub fn open(
+        db_file: String,
+    ) -> Result<DBProcessor, KoalaError> {
+        let mut connection = Connection::open_with_flags(
+            &db_file,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        )?;
+        set_pragmas(&connection)?;
+        migrate(&mut connection)?;
 
         Ok(DBProcessor {
-            txs: Vec::new(),
-            connection
+            connection,
+            config: EngineConfig::default(),
+            in_batch: false,
         })
     }
 
+    /// Replaces the default engine settings
+    pub fn with_config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Returns the transactions kept for a client,
+    /// in the order they were applied
+    pub fn client_transactions(
+        &self,
+        client_id: u16,
+    ) -> Result<Vec<Transaction>, KoalaError> {
+        let query = format!("SELECT type, client, tx, amount, reason, state FROM {} WHERE client=?1 ORDER BY seq", TX_TABLE_NAME);
+        let mut stmt = self.connection.prepare(&query)?;
+        let transactions = stmt
+            .query_map([client_id], read_transaction)?
+            .collect::<Result<Vec<Transaction>, rusqlite::Error>>()?;
+        Ok(transactions)
+    }
+
     /// Given the record, creates a new record in the db
     fn create_new_record(&self, cr: &ClientRecord) -> Result<(), KoalaError> {
-        println!("Creating new record: {:?}", cr);
+        trace!(?cr, "creating record");
         let query = 
         "INSERT INTO CLIENT_RECORDS ( id, available, held, total, locked ) VALUES (?1, ?2, ?3, ?4, ?5)";
         self.connection.execute(query, params![cr.id, cr.available, cr.held, cr.total, cr.locked])?;
@@ -45,155 +114,147 @@ impl DBProcessor {
         let query =
             "UPDATE CLIENT_RECORDS SET available=?1, held=?2, total=?3, locked=?4 where id=?5";
         
-        if let Ok(_) = self.connection.execute(query, params![cr.available, cr.held, cr.total, cr.locked, cr.id]) {
-            return Ok(());
-        } 
-        Err(KoalaError::PartnerError)
-    }
-    
-    /// Checks if the client exists, given an client id
-    fn client_exists(&self, id: u64) -> bool {
-        let query =
-        format!("SELECT 1 FROM {} WHERE id={}", TABLE_NAME, id);
-        let record: u16 = self.connection.query_row(&query, [], |row| row.get(0)).unwrap_or_default();
-        record == 1
+        self.connection.execute(query, params![cr.available, cr.held, cr.total, cr.locked, cr.id])?;
+        Ok(())
     }
 }
 
-impl Engine for DBProcessor {
-    /// Deposit transaction
-    fn deposit_tx(&mut self, tx: Transaction) -> Result<(), KoalaError> {
-        self.print_tx_header("deposit");
-        
-        let client_id = tx.client;
-        let mut client_record = self.get_client_record(client_id)?;
-
-        if client_record.locked {
-            return Err(KoalaError::AccountLockedError);
-        }
-
-        let amount = match tx.amount {
-            Some(amt) => amt,
-            None => 0.0
-        };
-        
-        self.txs.push(tx);
-
-        if amount > 0.0 {
-            client_record.available += amount;
-            client_record.total = client_record.available + client_record.held;
-            self.update_record(&client_record)?;
-            return Ok(());
-        }
-
+/// Switches to write-ahead logging, which only needs to sync
+/// the log on commit rather than on every write
+fn set_pragmas(connection: &Connection) -> Result<(), KoalaError> {
+    connection.query_row("PRAGMA journal_mode=WAL", [], |_| Ok(()))?;
+    connection.execute("PRAGMA synchronous=NORMAL", ())?;
+    Ok(())
+}
 
-        Err(KoalaError::PartnerError)
+/// Brings the schema up to date, applying every migration newer than
+/// the database's user_version, each in its own transaction
+fn migrate(connection: &mut Connection) -> Result<(), KoalaError> {
+    // Bound as i64, sqlite integers are signed
+    let version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if !(0..=MIGRATIONS.len() as i64).contains(&version) {
+        return Err(KoalaError::SchemaError(format!(
+            "database is at version {}, this engine only knows up to {}",
+            version,
+            MIGRATIONS.len()
+        )));
     }
 
-    /// Withdrawal transaction
-    fn withdrawal_tx(&mut self, tx: Transaction) -> Result<(), KoalaError> {
-        self.print_tx_header("withdrawal");
-
-        let client_id = tx.client;
-        let mut client_record = self.get_client_record(client_id)?;
-
-        if client_record.locked {
-            return Err(KoalaError::AccountLockedError);
-        }
-
-        let amount = match tx.amount {
-            Some(amt) => amt,
-            None => 0.0
-        };
-
-        self.txs.push(tx);
-
-        if amount <= client_record.available {
-            client_record.available -= amount;
-            client_record.total = client_record.available + client_record.held;
-            self.update_record(&client_record)?;
-            return Ok(());
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = connection.transaction()?;
+        if index == 0 && has_real_amounts(&tx)? {
+            info!("converting REAL balances to minor units");
+            tx.execute_batch(LEGACY_REAL_BALANCES)?;
         }
-
-        Err(KoalaError::BalanceError)
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index as i64 + 1)?;
+        tx.commit()?;
+        info!(version = index + 1, "migrated the database");
     }
+    Ok(())
+}
 
-    /// Dispute transaction
-    fn dispute_tx(&self, tx: &Transaction) -> Result<(), KoalaError> {
-        self.print_tx_header("dispute");
-        let client_id = tx.client;
-        let mut client_record = self.get_client_record(client_id)?;
-
-        if client_record.locked {
-            return Err(KoalaError::AccountLockedError);
-        }
+/// Whether the client records keep their balances as REAL,
+/// as the first releases did
+fn has_real_amounts(connection: &Connection) -> Result<bool, KoalaError> {
+    let query = "SELECT count(*) FROM pragma_table_info(?1) WHERE type = 'REAL'";
+    let columns: i64 = connection.query_row(query, [TABLE_NAME], |row| row.get(0))?;
+    Ok(columns > 0)
+}
 
-        if let Some(transaction) = self.find_original_transaction(&tx.tx) {
-            let amount = transaction.amount.unwrap_or_default();
-            client_record.available -= amount;
-            client_record.held += amount;
-            client_record.total = client_record.available + client_record.held;
-            self.update_record(&client_record)?;
-            return Ok(());
-        }
+/// Builds a transaction from a row of
+/// type, client, tx, amount, reason and state
+fn read_transaction(row: &rusqlite::Row) -> rusqlite::Result<Transaction> {
+    let mut transaction = Transaction::new(row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?);
+    transaction.reason = row.get(4)?;
+    transaction.state = row.get(5)?;
+    Ok(transaction)
+}
 
-        Err(KoalaError::PartnerError)
+impl ToSql for DisputeState {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
     }
+}
 
-    /// Resolve transaction
-    fn resolve_tx(&self, tx: &Transaction) -> Result<(), KoalaError> {
-        self.print_tx_header("resolve");
-
-        let client_id = tx.client;
-        let mut client_record = self.get_client_record(client_id)?;
-
-        if client_record.locked {
-            return Err(KoalaError::AccountLockedError);
-        }
-
-        if let Some(transaction) = self.find_original_transaction(&tx.tx) {
-            let amount = transaction.amount.unwrap_or_default();
-            client_record.held -= amount;
-            client_record.available += amount;
-            client_record.total = client_record.available + client_record.held;
-            self.update_record(&client_record)?;
-            return Ok(());
-        }
-
-        Err(KoalaError::PartnerError)
+impl FromSql for DisputeState {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value.as_str()?.parse().map_err(|err| FromSqlError::Other(Box::new(err)))
     }
+}
 
-    /// Chargeback transaction
-    fn chargeback_tx(&self, tx: &Transaction) -> Result<(), KoalaError> {
-        self.print_tx_header("chargeback");
+impl ToSql for TransactionKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
 
-        let client_id = tx.client;
-        let mut client_record = self.get_client_record(client_id)?;
+impl FromSql for TransactionKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value.as_str()?.parse().map_err(|err| FromSqlError::Other(Box::new(err)))
+    }
+}
 
-        if client_record.locked {
-            return Err(KoalaError::AccountLockedError);
-        }
+/// Returns every client record of a database
+/// written by a previous run, sorted by client id
+pub fn read_client_records(
+    db_file: &str,
+) -> Result<Vec<ClientRecord>, KoalaError> {
+    let connection =
+        Connection::open_with_flags(db_file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let query = format!(
+        "SELECT id, available, held, total, locked FROM {} ORDER BY id",
+        TABLE_NAME
+    );
+    let mut stmt = connection.prepare(&query)?;
+    let records = stmt
+        .query_map([], |row| {
+            Ok(ClientRecord {
+                id: row.get(0)?,
+                available: row.get(1)?,
+                held: row.get(2)?,
+                total: row.get(3)?,
+                locked: row.get(4)?,
+            })
+        })?
+        .collect::<Result<Vec<ClientRecord>, rusqlite::Error>>()?;
+    Ok(records)
+}
 
-        if let Some(transaction) = self.find_original_transaction(&tx.tx) {
-            let amount = transaction.amount.unwrap_or_default();
-            client_record.held -= amount;
-            client_record.locked = true;
-            client_record.total = client_record.available + client_record.held;
-            self.update_record(&client_record)?;
-            return Ok(());
-        }
+impl Engine for DBProcessor {
+    fn config(&self) -> &EngineConfig {
+        &self.config
+    }
 
-        Err(KoalaError::PartnerError)
+    /// Keeps the transaction for later disputes
+    fn store_transaction(&mut self, tx: Transaction) -> Result<(), KoalaError> {
+        let query = format!("INSERT INTO {} ( tx, client, type, amount, reason, state ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)", TX_TABLE_NAME);
+        self.connection.execute(&query, params![tx.tx, tx.client, tx.tx_type, tx.amount, tx.reason, tx.state])?;
+        Ok(())
     }
 
     /// Returns a transaction, given a transaction id
-    fn find_original_transaction(&self, tx_id: &str) -> Option<&Transaction> {
-        for transaction in &self.txs {
-            if transaction.tx == tx_id {
-                return Some(&transaction);
-            }
-        }
-        None
+    fn find_original_transaction(
+        &self,
+        tx_id: &str,
+    ) -> Result<Option<Transaction>, KoalaError> {
+        let query = format!("SELECT type, client, tx, amount, reason, state FROM {} WHERE tx=?1", TX_TABLE_NAME);
+        let transaction = self
+            .connection
+            .query_row(&query, [tx_id], read_transaction)
+            .optional()?;
+        Ok(transaction)
+    }
+
+    /// Updates the dispute state of a transaction
+    fn set_dispute_state(
+        &mut self,
+        tx_id: &str,
+        state: DisputeState,
+    ) -> Result<(), KoalaError> {
+        let query = format!("UPDATE {} SET state=?1 WHERE tx=?2", TX_TABLE_NAME);
+        self.connection.execute(&query, params![state, tx_id])?;
+        Ok(())
     }
 
     /// Returns a client record, given a client id
@@ -230,30 +291,107 @@ impl Engine for DBProcessor {
 
         let client_record = ClientRecord {
             id: client_id,
-            available: 0.0,
-            held: 0.0,
-            total: 0.0,
+            available: Money::ZERO,
+            held: Money::ZERO,
+            total: Money::ZERO,
             locked: false,
         };
 
         Ok(client_record)
     }
 
+    /// Whether the client has a record
+    fn client_exists(&self, client_id: u16) -> Result<bool, KoalaError> {
+        let query = format!("SELECT 1 FROM {} WHERE id=?1", TABLE_NAME);
+        let found = self
+            .connection
+            .query_row(&query, [client_id], |_| Ok(()))
+            .optional()?;
+        Ok(found.is_some())
+    }
+
     /// Updates the client record, with the new record
-    fn update_record(&self, cr: &ClientRecord) -> Result<(), KoalaError> {
-        println!("Updating - {:?}", cr);
+    fn update_record(&mut self, cr: &ClientRecord) -> Result<(), KoalaError> {
+        trace!(?cr, "updating record");
 
-        let existing_client = self.client_exists(cr.id.into());        
+        let existing_client = self.client_exists(cr.id)?;
         match existing_client {
-            true => {
-                self.update_existing_record(cr)?;
-                return Ok(());
-            },
-            false => {
-                self.create_new_record(cr)?;
-                return Ok(());
-            }
-        }        
+            true => self.update_existing_record(cr),
+            false => self.create_new_record(cr),
+        }
+    }
+
+    /// Opens a savepoint for the transaction, inside the
+    /// batch transaction which is started when needed
+    fn begin_tx(&mut self) -> Result<(), KoalaError> {
+        if !self.in_batch {
+            self.connection.execute("BEGIN", ())?;
+            self.in_batch = true;
+        }
+        self.connection.execute("SAVEPOINT tx", ())?;
+        Ok(())
+    }
+
+    /// Releases the savepoint, keeping its changes in the batch
+    fn commit_tx(&mut self) -> Result<(), KoalaError> {
+        self.connection.execute("RELEASE tx", ())?;
+        Ok(())
+    }
+
+    /// Rolls back to the savepoint and releases it
+    fn rollback_tx(&mut self) -> Result<(), KoalaError> {
+        self.connection.execute("ROLLBACK TO tx", ())?;
+        self.connection.execute("RELEASE tx", ())?;
+        Ok(())
+    }
+
+    /// Commits the batch transaction, if one is open
+    fn commit_batch(&mut self) -> Result<(), KoalaError> {
+        if self.in_batch {
+            self.connection.execute("COMMIT", ())?;
+            self.in_batch = false;
+        }
+        Ok(())
+    }
+
+    fn keeps_checkpoints(&self) -> bool {
+        true
+    }
+
+    /// Returns the checkpoint of the last run, if any
+    fn load_checkpoint(&self) -> Result<Option<Checkpoint>, KoalaError> {
+        let query = format!("SELECT input_hash, line, quarantined FROM {} WHERE id=0", CHECKPOINT_TABLE_NAME);
+        let checkpoint = self
+            .connection
+            .query_row(&query, [], |row| {
+                Ok((row.get(0)?, row.get::<_, i64>(1)?, row.get::<_, String>(2)?))
+            })
+            .optional()?;
+        let Some((input_hash, line, quarantined)) = checkpoint else {
+            return Ok(None);
+        };
+        let quarantined = quarantined
+            .split(',')
+            .filter(|id| !id.is_empty())
+            .map(|id| id.parse())
+            .collect::<Result<BTreeSet<u16>, _>>()
+            .map_err(|_| {
+                KoalaError::SchemaError(format!("invalid quarantined clients {:?} in the checkpoint", quarantined))
+            })?;
+        // The line is kept as a signed sqlite integer
+        Ok(Some(Checkpoint {
+            input_hash,
+            line: line as u64,
+            quarantined,
+        }))
+    }
+
+    /// Replaces the checkpoint with the new one
+    fn save_checkpoint(&mut self, checkpoint: &Checkpoint) -> Result<(), KoalaError> {
+        let query = format!("INSERT OR REPLACE INTO {} ( id, input_hash, line, quarantined ) VALUES (0, ?1, ?2, ?3)", CHECKPOINT_TABLE_NAME);
+        let quarantined: Vec<String> = checkpoint.quarantined.iter().map(|id| id.to_string()).collect();
+        self.connection.execute(&query, params![checkpoint.input_hash, checkpoint.line as i64, quarantined.join(",")])?;
+        Ok(())
     }
 }
 
@@ -268,10 +406,10 @@ mod tests {
     #[test]
     fn test_deposit_tx() {
         let (mut processor, db_file) = prep_test();
-        let tx_type = String::from("deposit");
+        let tx_type = TransactionKind::Deposit;
         let client_id = 1;
         let tx_id = String::from("1");
-        let amount = 10.0;
+        let amount: Money = "10.0".parse().unwrap();
 
         let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
         processor.deposit_tx(tx).unwrap();
@@ -281,7 +419,7 @@ mod tests {
 
         assert_eq!(cr.available, amount);
         assert_eq!(cr.total, amount);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.held, Money::ZERO);
 
         cleanup(db_file);
     }
@@ -291,10 +429,10 @@ mod tests {
         let (mut processor, db_file) = prep_test();
 
         // We must do a deposit first
-        let tx_type = String::from("deposit");
+        let tx_type = TransactionKind::Deposit;
         let client_id = 1;
         let tx_id = String::from("1");
-        let amount = 10.0;
+        let amount: Money = "10.0".parse().unwrap();
         let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
         processor.deposit_tx(tx).unwrap();
 
@@ -302,19 +440,19 @@ mod tests {
         let cr = processor.get_client_record(client_id).unwrap();
         assert_eq!(cr.available, amount);
         assert_eq!(cr.total, amount);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.held, Money::ZERO);
 
         // Withdrawal now
-        let tx_type = String::from("withdrawal");
+        let tx_type = TransactionKind::Withdrawal;
         let tx_id = String::from("2");
         let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
         processor.withdrawal_tx(tx).unwrap();
 
         // Verify the result
         let cr = processor.get_client_record(client_id).unwrap();
-        assert_eq!(cr.available, 0.0);
-        assert_eq!(cr.total, 0.0);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.available, Money::ZERO);
+        assert_eq!(cr.total, Money::ZERO);
+        assert_eq!(cr.held, Money::ZERO);
 
         cleanup(db_file);
     }
@@ -324,10 +462,10 @@ mod tests {
         let (mut processor, db_file) = prep_test();
 
         // We must do a deposit first
-        let tx_type = String::from("deposit");
+        let tx_type = TransactionKind::Deposit;
         let client_id = 1;
         let tx_id = String::from("1");
-        let amount = 10.0;
+        let amount: Money = "10.0".parse().unwrap();
         let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
         processor.deposit_tx(tx).unwrap();
 
@@ -335,17 +473,17 @@ mod tests {
         let cr = processor.get_client_record(client_id).unwrap();
         assert_eq!(cr.available, amount);
         assert_eq!(cr.total, amount);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.held, Money::ZERO);
 
         // Dispute now
-        let tx_type = String::from("dispute");
+        let tx_type = TransactionKind::Dispute;
         let tx_id = String::from("1");
         let tx = Transaction::new(tx_type, client_id, tx_id, None);
         processor.dispute_tx(&tx).unwrap();
 
         // Verify the result
         let cr = processor.get_client_record(client_id).unwrap();
-        assert_eq!(cr.available, 0.0);
+        assert_eq!(cr.available, Money::ZERO);
         assert_eq!(cr.total, amount);
         assert_eq!(cr.held, amount);
 
@@ -357,10 +495,10 @@ mod tests {
         let (mut processor, db_file) = prep_test();
 
         // We must do a deposit first
-        let tx_type = String::from("deposit");
+        let tx_type = TransactionKind::Deposit;
         let client_id = 1;
         let tx_id = String::from("1");
-        let amount = 10.0;
+        let amount: Money = "10.0".parse().unwrap();
         let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
         processor.deposit_tx(tx).unwrap();
 
@@ -368,22 +506,22 @@ mod tests {
         let cr = processor.get_client_record(client_id).unwrap();
         assert_eq!(cr.available, amount);
         assert_eq!(cr.total, amount);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.held, Money::ZERO);
 
         // Dispute now
-        let tx_type = String::from("dispute");
+        let tx_type = TransactionKind::Dispute;
         let tx_id = String::from("1");
         let tx = Transaction::new(tx_type, client_id, tx_id, None);
         processor.dispute_tx(&tx).unwrap();
 
         // Verify the result
         let cr = processor.get_client_record(client_id).unwrap();
-        assert_eq!(cr.available, 0.0);
+        assert_eq!(cr.available, Money::ZERO);
         assert_eq!(cr.total, amount);
         assert_eq!(cr.held, amount);
 
         // Resolve now
-        let tx_type = String::from("resolve");
+        let tx_type = TransactionKind::Resolve;
         let tx_id = String::from("1");
         let tx = Transaction::new(tx_type, client_id, tx_id, None);
         processor.resolve_tx(&tx).unwrap();
@@ -392,7 +530,7 @@ mod tests {
         let cr = processor.get_client_record(client_id).unwrap();
         assert_eq!(cr.available, amount);
         assert_eq!(cr.total, amount);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.held, Money::ZERO);
 
         cleanup(db_file);
     }
@@ -402,10 +540,10 @@ mod tests {
         let (mut processor, db_file) = prep_test();
 
         // We must do a deposit first
-        let tx_type = String::from("deposit");
+        let tx_type = TransactionKind::Deposit;
         let client_id = 1;
         let tx_id = String::from("1");
-        let amount = 10.0;
+        let amount: Money = "10.0".parse().unwrap();
         let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
         processor.deposit_tx(tx).unwrap();
 
@@ -413,37 +551,183 @@ mod tests {
         let cr = processor.get_client_record(client_id).unwrap();
         assert_eq!(cr.available, amount);
         assert_eq!(cr.total, amount);
-        assert_eq!(cr.held, 0.0);
+        assert_eq!(cr.held, Money::ZERO);
 
         // Dispute now
-        let tx_type = String::from("dispute");
+        let tx_type = TransactionKind::Dispute;
         let tx_id = String::from("1");
         let tx = Transaction::new(tx_type, client_id, tx_id, None);
         processor.dispute_tx(&tx).unwrap();
 
         // Verify the result
         let cr = processor.get_client_record(client_id).unwrap();
-        assert_eq!(cr.available, 0.0);
+        assert_eq!(cr.available, Money::ZERO);
         assert_eq!(cr.total, amount);
         assert_eq!(cr.held, amount);
 
         // Chargeback now
-        let tx_type = String::from("chargeback");
+        let tx_type = TransactionKind::Chargeback;
         let tx_id = String::from("1");
         let tx = Transaction::new(tx_type, client_id, tx_id, None);
         processor.chargeback_tx(&tx).unwrap();
 
         // Verify the result
         let cr = processor.get_client_record(client_id).unwrap();
-        assert_eq!(cr.available, 0.0);
-        assert_eq!(cr.total, 0.0);
-        assert_eq!(cr.held, 0.0);
-        assert_eq!(cr.locked, true);
+        assert_eq!(cr.available, Money::ZERO);
+        assert_eq!(cr.total, Money::ZERO);
+        assert_eq!(cr.held, Money::ZERO);
+        assert!(cr.locked);
 
         cleanup(db_file);
     }    
 
     
+    #[test]
+    fn test_find_original_transaction() {
+        let (mut processor, db_file) = prep_test();
+
+        let amount: Money = "10.0".parse().unwrap();
+        let tx = Transaction::new(TransactionKind::Deposit, 1, String::from("1"), Some(amount));
+        processor.deposit_tx(tx).unwrap();
+
+        let found = processor.find_original_transaction("1").unwrap().unwrap();
+        assert_eq!(found.client, 1);
+        assert_eq!(found.amount, Some(amount));
+        assert!(processor.find_original_transaction("2").unwrap().is_none());
+
+        // The lookup must use the index rather than scan the table
+        let query = format!("EXPLAIN QUERY PLAN SELECT type, client, tx, amount FROM {} WHERE tx=?1", TX_TABLE_NAME);
+        let plan: String = processor
+            .connection
+            .query_row(&query, ["1"], |row| row.get(3))
+            .unwrap();
+        assert!(plan.contains("USING INDEX"), "{}", plan);
+
+        cleanup(db_file);
+    }
+
+    #[test]
+    fn test_client_transactions() {
+        let (mut processor, db_file) = prep_test();
+
+        let amount: Money = "10.0".parse().unwrap();
+        for (client_id, tx_id) in [(2, "9"), (1, "3"), (2, "1")] {
+            let tx = Transaction::new(TransactionKind::Deposit, client_id, tx_id.into(), Some(amount));
+            processor.deposit_tx(tx).unwrap();
+        }
+
+        // In the order applied, not by id
+        let history: Vec<String> = processor
+            .client_transactions(2)
+            .unwrap()
+            .into_iter()
+            .map(|tx| tx.tx)
+            .collect();
+        assert_eq!(history, vec!["9", "1"]);
+
+        // Every row is numbered and stamped
+        let query = format!("SELECT seq, created_at FROM {} WHERE tx='1'", TX_TABLE_NAME);
+        let (seq, created_at): (i64, String) = processor
+            .connection
+            .query_row(&query, [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!(seq, 3);
+        assert!(created_at.ends_with('Z'), "{}", created_at);
+
+        let query = format!("EXPLAIN QUERY PLAN SELECT tx FROM {} WHERE client=?1 ORDER BY seq", TX_TABLE_NAME);
+        let plan: String = processor
+            .connection
+            .query_row(&query, [2], |row| row.get(3))
+            .unwrap();
+        assert!(plan.contains("TRANSACTIONS_CLIENT"), "{}", plan);
+
+        cleanup(db_file);
+    }
+
+    #[test]
+    fn test_open_keeps_previous_state() {
+        let (mut processor, db_file) = prep_test();
+        let amount: Money = "10.0".parse().unwrap();
+        let tx = Transaction::new(TransactionKind::Deposit, 1, "1".into(), Some(amount));
+        processor.deposit_tx(tx).unwrap();
+        drop(processor);
+
+        // A later session sees the balance and can dispute the deposit
+        let mut processor = DBProcessor::open(db_file.clone()).unwrap();
+        let cr = processor.get_client_record(1).unwrap();
+        assert_eq!(cr.available, amount);
+        let tx = Transaction::new(TransactionKind::Dispute, 1, "1".into(), None);
+        processor.dispute_tx(&tx).unwrap();
+        let cr = processor.get_client_record(1).unwrap();
+        assert_eq!(cr.held, amount);
+
+        // new() still expects a fresh database
+        drop(processor);
+        assert!(DBProcessor::new(db_file.clone()).is_err());
+
+        cleanup(db_file);
+    }
+
+    #[test]
+    fn test_open_upgrades_older_database() {
+        let rnum: u32 = thread_rng().gen();
+        let db_file = format!("{}_{}", TMP_DB, rnum);
+
+        // Laid out by the first releases, with REAL balances
+        let connection = Connection::open(&db_file).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE CLIENT_RECORDS ( id INTEGER PRIMARY KEY, available REAL, held REAL, total REAL, locked BOOL );
+                 INSERT INTO CLIENT_RECORDS VALUES (1, 15.0, 0.0, 15.0, 0);
+                 INSERT INTO CLIENT_RECORDS VALUES (2, 262812.1875, 38496.390625, 301308.578125, 1);",
+            )
+            .unwrap();
+        drop(connection);
+
+        let mut processor = DBProcessor::open(db_file.clone()).unwrap();
+        let version: i64 = processor
+            .connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+
+        let cr = processor.get_client_record(2).unwrap();
+        assert_eq!(cr.available, "262812.1875".parse().unwrap());
+        assert_eq!(cr.held, "38496.3906".parse().unwrap());
+        assert_eq!(cr.total, "301308.5781".parse().unwrap());
+        assert!(cr.locked);
+
+        // The balances carry on in minor units
+        let amount: Money = "10.0".parse().unwrap();
+        let tx = Transaction::new(TransactionKind::Deposit, 1, "7".into(), Some(amount));
+        processor.deposit_tx(tx).unwrap();
+        let tx = Transaction::new(TransactionKind::Dispute, 1, "7".into(), None);
+        processor.dispute_tx(&tx).unwrap();
+        let cr = processor.get_client_record(1).unwrap();
+        assert_eq!((cr.available, cr.held), ("15.0".parse().unwrap(), amount));
+        drop(processor);
+
+        // Opening again has nothing left to do
+        DBProcessor::open(db_file.clone()).unwrap();
+
+        cleanup(db_file);
+    }
+
+    #[test]
+    fn test_open_refuses_newer_database() {
+        let (processor, db_file) = prep_test();
+        processor
+            .connection
+            .pragma_update(None, "user_version", MIGRATIONS.len() as i64 + 1)
+            .unwrap();
+        drop(processor);
+
+        let result = DBProcessor::open(db_file.clone());
+        assert!(matches!(result, Err(KoalaError::SchemaError(_))));
+
+        cleanup(db_file);
+    }
+
     fn prep_test() -> (self::DBProcessor, String) {
         let mut rng = thread_rng();
         let rnum: u32 = rng.gen();        
@@ -459,9 +743,13 @@ mod tests {
     }
 
     fn cleanup(db_file: String) {
-        if std::path::Path::new(db_file.as_str()).exists() {
-            println!("Cleaning {}", db_file);
-            std::fs::remove_file(db_file).unwrap();
+        // The write-ahead log files go along with the db
+        for suffix in ["", "-wal", "-shm"] {
+            let file = format!("{}{}", db_file, suffix);
+            if std::path::Path::new(file.as_str()).exists() {
+                println!("Cleaning {}", file);
+                std::fs::remove_file(file).unwrap();
+            }
         }
     }
 }
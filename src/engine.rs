@@ -1,63 +1,680 @@
 use crate::errors::KoalaError;
-use serde::{Deserialize, Serialize};
+use crate::money::Money;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+use tracing::debug;
 
 /// Represents a transaction
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Transaction {
     #[serde(rename = "type")]
-    pub tx_type: String,
+    pub tx_type: TransactionKind,
     #[serde(rename = "client")]
     pub client: u16,
     pub tx: String,
-    #[serde(deserialize_with = "csv::invalid_option")]
-    pub amount: Option<f32>,
+    pub amount: Option<Money>,
+    /// Why an administrative transaction was made
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(skip)]
+    pub state: DisputeState,
+}
+
+/// Kind of a transaction, parsed case-insensitively
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransactionKind {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Freeze,
+    Unlock,
+    Adjustment,
+}
+
+impl TransactionKind {
+    pub const ALL: [TransactionKind; 8] = [
+        TransactionKind::Deposit,
+        TransactionKind::Withdrawal,
+        TransactionKind::Dispute,
+        TransactionKind::Resolve,
+        TransactionKind::Chargeback,
+        TransactionKind::Freeze,
+        TransactionKind::Unlock,
+        TransactionKind::Adjustment,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TransactionKind::Deposit => "deposit",
+            TransactionKind::Withdrawal => "withdrawal",
+            TransactionKind::Dispute => "dispute",
+            TransactionKind::Resolve => "resolve",
+            TransactionKind::Chargeback => "chargeback",
+            TransactionKind::Freeze => "freeze",
+            TransactionKind::Unlock => "unlock",
+            TransactionKind::Adjustment => "adjustment",
+        }
+    }
+
+    /// Whether a transaction of this kind must have an amount,
+    /// the others must not have one
+    pub fn needs_amount(self) -> bool {
+        matches!(
+            self,
+            TransactionKind::Deposit
+                | TransactionKind::Withdrawal
+                | TransactionKind::Adjustment
+        )
+    }
+
+    /// Whether this kind is made by an operator rather than a client
+    pub fn is_admin(self) -> bool {
+        matches!(
+            self,
+            TransactionKind::Freeze
+                | TransactionKind::Unlock
+                | TransactionKind::Adjustment
+        )
+    }
+}
+
+impl fmt::Display for TransactionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for TransactionKind {
+    type Err = KoalaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TransactionKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| KoalaError::InputError(s.to_string()))
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionKind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KindVisitor;
+
+        impl<'de> Visitor<'de> for KindVisitor {
+            type Value = TransactionKind;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a transaction type, one of ")?;
+                let names: Vec<&str> =
+                    TransactionKind::ALL.iter().map(|kind| kind.as_str()).collect();
+                write!(f, "{}", names.join(", "))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<TransactionKind, E> {
+                v.parse()
+                    .map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+            }
+        }
+
+        deserializer.deserialize_str(KindVisitor)
+    }
+}
+
+/// Dispute state of a kept transaction. A transaction goes from
+/// normal to disputed, and from disputed to either resolved or
+/// charged back, both of which are final.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisputeState {
+    #[default]
+    Normal,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl DisputeState {
+    /// Returns the state after a dispute, if allowed
+    pub fn dispute(self) -> Option<DisputeState> {
+        match self {
+            DisputeState::Normal => Some(DisputeState::Disputed),
+            _ => None,
+        }
+    }
+
+    /// Returns the state after a resolve, if allowed
+    pub fn resolve(self) -> Option<DisputeState> {
+        match self {
+            DisputeState::Disputed => Some(DisputeState::Resolved),
+            _ => None,
+        }
+    }
+
+    /// Returns the state after a chargeback, if allowed
+    pub fn chargeback(self) -> Option<DisputeState> {
+        match self {
+            DisputeState::Disputed => Some(DisputeState::ChargedBack),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DisputeState::Normal => "normal",
+            DisputeState::Disputed => "disputed",
+            DisputeState::Resolved => "resolved",
+            DisputeState::ChargedBack => "chargedback",
+        }
+    }
+}
+
+impl FromStr for DisputeState {
+    type Err = KoalaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(DisputeState::Normal),
+            "disputed" => Ok(DisputeState::Disputed),
+            "resolved" => Ok(DisputeState::Resolved),
+            "chargedback" => Ok(DisputeState::ChargedBack),
+            _ => Err(KoalaError::InputError(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for DisputeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 /// Represents individual client record
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ClientRecord {
     #[serde(rename = "client")]
     pub id: u16,
-    pub available: f32,
-    pub held: f32,
-    pub total: f32,
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    /// Read in any case, the tables written by
+    /// `utils/tx_gen.py` have `True` and `False`
+    #[serde(deserialize_with = "deserialize_locked")]
     pub locked: bool,
 }
 
+/// Reads the locked flag of a client record, in any case
+fn deserialize_locked<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<bool, D::Error> {
+    struct LockedVisitor;
+
+    impl<'de> Visitor<'de> for LockedVisitor {
+        type Value = bool;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "true or false")
+        }
+
+        fn visit_bool<E: de::Error>(self, v: bool) -> Result<bool, E> {
+            Ok(v)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<bool, E> {
+            match v.trim() {
+                v if v.eq_ignore_ascii_case("true") => Ok(true),
+                v if v.eq_ignore_ascii_case("false") => Ok(false),
+                _ => Err(E::invalid_value(de::Unexpected::Str(v), &self)),
+            }
+        }
+    }
+
+    deserializer.deserialize_str(LockedVisitor)
+}
+
 impl Transaction {
     pub fn new(
-        tx_type: String,
+        tx_type: TransactionKind,
         client: u16,
         tx: String,
-        amount: Option<f32>,
+        amount: Option<Money>,
     ) -> Self {
         Transaction {
             tx_type,
             client,
             tx,
             amount,
+            reason: None,
+            state: DisputeState::Normal,
+        }
+    }
+
+    /// Checks the amount is given if the kind needs one,
+    /// and left out otherwise. Every transaction function
+    /// checks it first.
+    pub fn check_amount(&self) -> Result<(), KoalaError> {
+        let (client, tx, kind) = (self.client, self.tx.clone(), self.tx_type);
+        match (kind.needs_amount(), self.amount) {
+            (true, None) => {
+                Err(KoalaError::AmountMissingError { client, tx, kind })
+            }
+            (false, Some(_)) => {
+                Err(KoalaError::AmountNotAllowedError { client, tx, kind })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the dispute state after the dispute, resolve or
+    /// chargeback action, refusing a transition the state
+    /// machine doesn't allow
+    pub fn next_state(
+        &self,
+        action: TransactionKind,
+    ) -> Result<DisputeState, KoalaError> {
+        let next = match action {
+            TransactionKind::Dispute => self.state.dispute(),
+            TransactionKind::Resolve => self.state.resolve(),
+            TransactionKind::Chargeback => self.state.chargeback(),
+            _ => None,
+        };
+        next.ok_or_else(|| KoalaError::DisputeStateError {
+            client: self.client,
+            tx: self.tx.clone(),
+            state: self.state,
+            action,
+        })
+    }
+
+    // Error for a transaction refused on a locked account
+    fn locked_error(&self) -> KoalaError {
+        KoalaError::AccountLockedError {
+            client: self.client,
+            tx: self.tx.clone(),
         }
     }
 }
 
-/// Engine trait governs the main transaction related functionality
+/// How far processing of an input file got, saved with the
+/// state so an interrupted run can be resumed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Sha-256 of the input file, in hex
+    pub input_hash: String,
+    /// Last input line whose transaction was committed
+    pub line: u64,
+    /// Clients quarantined up to that line, whose later
+    /// transactions a resumed run still holds back
+    pub quarantined: BTreeSet<u16>,
+}
+
+/// What happens to a transaction reusing the id of an
+/// earlier deposit, withdrawal or administrative transaction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Refuse the transaction
+    #[default]
+    Reject,
+    /// Ignore an exact repeat of the earlier transaction,
+    /// refuse one that differs from it
+    Replay,
+}
+
+/// What a transaction function did with a transaction it accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    /// The transaction was applied to the account
+    Applied,
+    /// An exact repeat ignored under the replay policy,
+    /// which left the account as it was
+    Ignored,
+}
+
+/// What happens when a withdrawal is disputed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WithdrawalDisputePolicy {
+    /// Only deposits can be disputed
+    #[default]
+    Reject,
+    /// The withdrawn funds come back as held while disputed.
+    /// A resolve lets the withdrawal stand, a chargeback
+    /// reverses it and locks the account.
+    ReverseAndHold,
+}
+
+/// Settings shared by every processor
+#[derive(Debug, Clone, Default)]
+pub struct EngineConfig {
+    pub duplicates: DuplicatePolicy,
+    pub withdrawal_disputes: WithdrawalDisputePolicy,
+}
+
+/// Engine trait governs the main transaction related functionality.
+///
+/// The transaction functions are shared by every processor, which
+/// only provide the storage of transactions and client records.
 pub trait Engine {
     // Deposit transaction
-    fn deposit_tx(&mut self, tx: Transaction) -> Result<(), KoalaError>;
+    fn deposit_tx(&mut self, tx: Transaction) -> Result<Effect, KoalaError> {
+        tx.check_amount()?;
+        if self.is_replay(&tx)? {
+            return Ok(Effect::Ignored);
+        }
+        let client_id = tx.client;
+        let mut client_record = self.get_client_record(client_id)?;
+
+        if client_record.locked {
+            return Err(tx.locked_error());
+        }
+
+        let amount = tx.amount.unwrap_or_default();
+
+        if amount.is_positive() {
+            client_record.available =
+                client_record.available.checked_add(amount)?;
+            client_record.total =
+                client_record.available.checked_add(client_record.held)?;
+            self.update_record(&client_record)?;
+            self.store_transaction(tx)?;
+            debug!(%amount, available = %client_record.available, "applied");
+            return Ok(Effect::Applied);
+        }
+
+        Err(KoalaError::AmountError {
+            client: tx.client,
+            tx: tx.tx,
+            amount,
+            expected: "more than zero",
+        })
+    }
 
     // Withdrawal transaction
-    fn withdrawal_tx(&mut self, tx: Transaction) -> Result<(), KoalaError>;
+    fn withdrawal_tx(&mut self, tx: Transaction) -> Result<Effect, KoalaError> {
+        tx.check_amount()?;
+        if self.is_replay(&tx)? {
+            return Ok(Effect::Ignored);
+        }
+        let client_id = tx.client;
+        let mut client_record = self.get_client_record(client_id)?;
+
+        if client_record.locked {
+            return Err(tx.locked_error());
+        }
+
+        let amount = tx.amount.unwrap_or_default();
+
+        if !amount.is_positive() {
+            return Err(KoalaError::AmountError {
+                client: tx.client,
+                tx: tx.tx,
+                amount,
+                expected: "more than zero",
+            });
+        }
+
+        if amount <= client_record.available {
+            client_record.available =
+                client_record.available.checked_sub(amount)?;
+            client_record.total =
+                client_record.available.checked_add(client_record.held)?;
+            self.update_record(&client_record)?;
+            self.store_transaction(tx)?;
+            debug!(%amount, available = %client_record.available, "applied");
+            return Ok(Effect::Applied);
+        }
+
+        Err(KoalaError::BalanceError {
+            client: tx.client,
+            tx: tx.tx,
+            available: client_record.available,
+            requested: amount,
+        })
+    }
 
     // Dispute transaction
-    fn dispute_tx(&self, tx: &Transaction) -> Result<(), KoalaError>;
+    fn dispute_tx(&mut self, tx: &Transaction) -> Result<Effect, KoalaError> {
+        tx.check_amount()?;
+        let transaction = self.find_referenced_transaction(tx)?;
+        let client_id = tx.client;
+        let mut client_record = self.get_client_record(client_id)?;
+
+        if client_record.locked {
+            return Err(tx.locked_error());
+        }
+
+        let is_withdrawal = transaction.tx_type == TransactionKind::Withdrawal;
+        if !is_withdrawal && transaction.tx_type != TransactionKind::Deposit {
+            return Err(KoalaError::NotDisputableError {
+                client: tx.client,
+                tx: tx.tx.clone(),
+                kind: transaction.tx_type,
+            });
+        }
+        let policy = self.config().withdrawal_disputes;
+        if is_withdrawal && policy == WithdrawalDisputePolicy::Reject {
+            return Err(KoalaError::WithdrawalDisputeError {
+                client: tx.client,
+                tx: tx.tx.clone(),
+            });
+        }
+
+        let state = transaction.next_state(TransactionKind::Dispute)?;
+        let amount = transaction.amount.unwrap_or_default();
+        if !is_withdrawal {
+            client_record.available =
+                client_record.available.checked_sub(amount)?;
+        }
+        // A disputed withdrawal brings its funds back as held
+        client_record.held = client_record.held.checked_add(amount)?;
+        client_record.total =
+            client_record.available.checked_add(client_record.held)?;
+        self.update_record(&client_record)?;
+        self.set_dispute_state(&tx.tx, state)?;
+        debug!(%amount, held = %client_record.held, %state, "applied");
+
+        Ok(Effect::Applied)
+    }
 
     // Resolve transaction
-    fn resolve_tx(&self, tx: &Transaction) -> Result<(), KoalaError>;
+    fn resolve_tx(&mut self, tx: &Transaction) -> Result<Effect, KoalaError> {
+        tx.check_amount()?;
+        let transaction = self.find_referenced_transaction(tx)?;
+        let client_id = tx.client;
+        let mut client_record = self.get_client_record(client_id)?;
+
+        if client_record.locked {
+            return Err(tx.locked_error());
+        }
+
+        let state = transaction.next_state(TransactionKind::Resolve)?;
+        let amount = transaction.amount.unwrap_or_default();
+        client_record.held = client_record.held.checked_sub(amount)?;
+        // A resolved withdrawal stands, so its held funds go again
+        if transaction.tx_type != TransactionKind::Withdrawal {
+            client_record.available =
+                client_record.available.checked_add(amount)?;
+        }
+        client_record.total =
+            client_record.available.checked_add(client_record.held)?;
+        self.update_record(&client_record)?;
+        self.set_dispute_state(&tx.tx, state)?;
+        debug!(%amount, held = %client_record.held, %state, "applied");
+
+        Ok(Effect::Applied)
+    }
 
     // Chargeback transaction
-    fn chargeback_tx(&self, tx: &Transaction) -> Result<(), KoalaError>;
+    fn chargeback_tx(&mut self, tx: &Transaction) -> Result<Effect, KoalaError> {
+        tx.check_amount()?;
+        let transaction = self.find_referenced_transaction(tx)?;
+        let client_id = tx.client;
+        let mut client_record = self.get_client_record(client_id)?;
 
-    fn find_original_transaction(&self, tx_id: &str) -> Option<&Transaction>;
+        if client_record.locked {
+            return Err(tx.locked_error());
+        }
+
+        let state = transaction.next_state(TransactionKind::Chargeback)?;
+        let amount = transaction.amount.unwrap_or_default();
+        client_record.held = client_record.held.checked_sub(amount)?;
+        // A charged back withdrawal is reversed, its funds are released
+        if transaction.tx_type == TransactionKind::Withdrawal {
+            client_record.available =
+                client_record.available.checked_add(amount)?;
+        }
+        client_record.locked = true;
+        client_record.total =
+            client_record.available.checked_add(client_record.held)?;
+        self.update_record(&client_record)?;
+        self.set_dispute_state(&tx.tx, state)?;
+        debug!(%amount, held = %client_record.held, %state, "applied");
+
+        Ok(Effect::Applied)
+    }
+
+    // Freeze transaction, locks the account on an operator's request
+    fn freeze_tx(&mut self, tx: Transaction) -> Result<Effect, KoalaError> {
+        tx.check_amount()?;
+        self.set_locked(tx, true)
+    }
+
+    // Unlock transaction, lets a locked account transact again
+    fn unlock_tx(&mut self, tx: Transaction) -> Result<Effect, KoalaError> {
+        tx.check_amount()?;
+        self.set_locked(tx, false)
+    }
+
+    // Adjustment transaction, an operator's correction of the
+    // available funds. Applies to locked accounts too.
+    fn adjustment_tx(&mut self, tx: Transaction) -> Result<Effect, KoalaError> {
+        tx.check_amount()?;
+        if self.is_replay(&tx)? {
+            return Ok(Effect::Ignored);
+        }
+
+        let amount = tx.amount.unwrap_or_default();
+        if amount == Money::ZERO {
+            return Err(KoalaError::AmountError {
+                client: tx.client,
+                tx: tx.tx,
+                amount,
+                expected: "not zero",
+            });
+        }
+        if tx.reason.as_ref().is_none_or(|r| r.is_empty()) {
+            return Err(KoalaError::ReasonMissingError {
+                client: tx.client,
+                tx: tx.tx,
+                kind: tx.tx_type,
+            });
+        }
+
+        let mut client_record = self.get_client_record(tx.client)?;
+        let available = client_record.available.checked_add(amount)?;
+        if available.is_negative() {
+            return Err(KoalaError::BalanceError {
+                client: tx.client,
+                tx: tx.tx,
+                available: client_record.available,
+                requested: Money::ZERO.checked_sub(amount)?,
+            });
+        }
+        client_record.available = available;
+        client_record.total =
+            client_record.available.checked_add(client_record.held)?;
+        self.update_record(&client_record)?;
+        debug!(%amount, reason = tx.reason.as_deref(), "applied");
+        self.store_transaction(tx)?;
+
+        Ok(Effect::Applied)
+    }
+
+    // Locks or unlocks the account of a freeze or unlock transaction
+    fn set_locked(
+        &mut self,
+        tx: Transaction,
+        locked: bool,
+    ) -> Result<Effect, KoalaError> {
+        if self.is_replay(&tx)? {
+            return Ok(Effect::Ignored);
+        }
+
+        let mut client_record = self.get_client_record(tx.client)?;
+        client_record.locked = locked;
+        self.update_record(&client_record)?;
+        self.store_transaction(tx)?;
+        debug!(locked, "applied");
+
+        Ok(Effect::Applied)
+    }
+
+    // Returns the transaction a dispute, resolve or chargeback
+    // refers to, which must belong to the same client
+    fn find_referenced_transaction(
+        &self,
+        tx: &Transaction,
+    ) -> Result<Transaction, KoalaError> {
+        let transaction = self.find_original_transaction(&tx.tx)?.ok_or_else(
+            || KoalaError::MissingTransactionError {
+                client: tx.client,
+                tx: tx.tx.clone(),
+            },
+        )?;
+
+        if transaction.client != tx.client {
+            return Err(KoalaError::ClientMismatchError {
+                client: tx.client,
+                tx: tx.tx.clone(),
+                owner: transaction.client,
+            });
+        }
+
+        Ok(transaction)
+    }
+
+    // Checks the id of a new transaction against the kept ones.
+    // Returns true for a replay the config allows to be ignored, and
+    // refuses any other reuse of the id.
+    fn is_replay(&self, tx: &Transaction) -> Result<bool, KoalaError> {
+        let original = match self.find_original_transaction(&tx.tx)? {
+            Some(original) => original,
+            None => return Ok(false),
+        };
+
+        let same = original.client == tx.client
+            && original.tx_type == tx.tx_type
+            && original.amount == tx.amount;
+        if same && self.config().duplicates == DuplicatePolicy::Replay {
+            debug!("ignored as a replay");
+            return Ok(true);
+        }
+
+        Err(KoalaError::DuplicateTransactionError {
+            client: tx.client,
+            tx: tx.tx.clone(),
+        })
+    }
+
+    // Settings the transaction functions follow
+    fn config(&self) -> &EngineConfig;
+
+    // Keep an applied transaction, so it can be disputed later
+    fn store_transaction(&mut self, tx: Transaction) -> Result<(), KoalaError>;
+
+    // Find a kept transaction, by id
+    fn find_original_transaction(
+        &self,
+        tx_id: &str,
+    ) -> Result<Option<Transaction>, KoalaError>;
+
+    // Update the dispute state of a kept transaction
+    fn set_dispute_state(
+        &mut self,
+        tx_id: &str,
+        state: DisputeState,
+    ) -> Result<(), KoalaError>;
 
     // Get client record
     fn get_client_record(
@@ -65,14 +682,184 @@ pub trait Engine {
         client_id: u16,
     ) -> Result<ClientRecord, KoalaError>;
 
+    // Whether the client has a record, rather than
+    // the empty one a new client gets
+    fn client_exists(&self, client_id: u16) -> Result<bool, KoalaError>;
+
     // Update client record
-    fn update_record(&self, cr: &ClientRecord) -> Result<(), KoalaError>;
-
-    // Print the transaction info for easy viewing
-    fn print_tx_header(&self, tx_type: &str) {
-        println!();
-        println!("==================================");
-        println!("Processing {} transaction", tx_type);
-        println!("==================================");
-    }    
+    fn update_record(&mut self, cr: &ClientRecord) -> Result<(), KoalaError>;
+
+    // Starts an atomic unit of work around one transaction
+    fn begin_tx(&mut self) -> Result<(), KoalaError> {
+        Ok(())
+    }
+
+    // Keeps the changes made since begin_tx
+    fn commit_tx(&mut self) -> Result<(), KoalaError> {
+        Ok(())
+    }
+
+    // Undoes the changes made since begin_tx
+    fn rollback_tx(&mut self) -> Result<(), KoalaError> {
+        Ok(())
+    }
+
+    // Makes every kept change durable, processors which write
+    // each change straight away have nothing to do
+    fn commit_batch(&mut self) -> Result<(), KoalaError> {
+        Ok(())
+    }
+
+    // Whether checkpoints are saved with the state, only then
+    // does a run need to hash its input
+    fn keeps_checkpoints(&self) -> bool {
+        false
+    }
+
+    // Returns the checkpoint saved with the state, processors
+    // which don't keep state between runs have none
+    fn load_checkpoint(&self) -> Result<Option<Checkpoint>, KoalaError> {
+        Ok(None)
+    }
+
+    // Saves how far processing got, along with the state
+    fn save_checkpoint(
+        &mut self,
+        _checkpoint: &Checkpoint,
+    ) -> Result<(), KoalaError> {
+        Ok(())
+    }
+
+    // Called once all transactions are processed, so processors
+    // that buffer client records can write out the final state
+    fn finish(&mut self) -> Result<(), KoalaError> {
+        Ok(())
+    }
+}
+
+/// Lends a processor to a processing run, so the caller keeps it
+/// for more runs or to look at the balances afterwards. Every method
+/// goes to the processor, so its overrides apply to the run too. The
+/// run commits its work but leaves finish to the owner, who calls it
+/// once after the last run.
+impl<E: Engine + ?Sized> Engine for &mut E {
+    fn deposit_tx(&mut self, tx: Transaction) -> Result<Effect, KoalaError> {
+        (**self).deposit_tx(tx)
+    }
+
+    fn withdrawal_tx(&mut self, tx: Transaction) -> Result<Effect, KoalaError> {
+        (**self).withdrawal_tx(tx)
+    }
+
+    fn dispute_tx(&mut self, tx: &Transaction) -> Result<Effect, KoalaError> {
+        (**self).dispute_tx(tx)
+    }
+
+    fn resolve_tx(&mut self, tx: &Transaction) -> Result<Effect, KoalaError> {
+        (**self).resolve_tx(tx)
+    }
+
+    fn chargeback_tx(&mut self, tx: &Transaction) -> Result<Effect, KoalaError> {
+        (**self).chargeback_tx(tx)
+    }
+
+    fn freeze_tx(&mut self, tx: Transaction) -> Result<Effect, KoalaError> {
+        (**self).freeze_tx(tx)
+    }
+
+    fn unlock_tx(&mut self, tx: Transaction) -> Result<Effect, KoalaError> {
+        (**self).unlock_tx(tx)
+    }
+
+    fn adjustment_tx(&mut self, tx: Transaction) -> Result<Effect, KoalaError> {
+        (**self).adjustment_tx(tx)
+    }
+
+    fn set_locked(
+        &mut self,
+        tx: Transaction,
+        locked: bool,
+    ) -> Result<Effect, KoalaError> {
+        (**self).set_locked(tx, locked)
+    }
+
+    fn find_referenced_transaction(
+        &self,
+        tx: &Transaction,
+    ) -> Result<Transaction, KoalaError> {
+        (**self).find_referenced_transaction(tx)
+    }
+
+    fn is_replay(&self, tx: &Transaction) -> Result<bool, KoalaError> {
+        (**self).is_replay(tx)
+    }
+
+    fn config(&self) -> &EngineConfig {
+        (**self).config()
+    }
+
+    fn store_transaction(&mut self, tx: Transaction) -> Result<(), KoalaError> {
+        (**self).store_transaction(tx)
+    }
+
+    fn find_original_transaction(
+        &self,
+        tx_id: &str,
+    ) -> Result<Option<Transaction>, KoalaError> {
+        (**self).find_original_transaction(tx_id)
+    }
+
+    fn set_dispute_state(
+        &mut self,
+        tx_id: &str,
+        state: DisputeState,
+    ) -> Result<(), KoalaError> {
+        (**self).set_dispute_state(tx_id, state)
+    }
+
+    fn get_client_record(
+        &self,
+        client_id: u16,
+    ) -> Result<ClientRecord, KoalaError> {
+        (**self).get_client_record(client_id)
+    }
+
+    fn client_exists(&self, client_id: u16) -> Result<bool, KoalaError> {
+        (**self).client_exists(client_id)
+    }
+
+    fn update_record(&mut self, cr: &ClientRecord) -> Result<(), KoalaError> {
+        (**self).update_record(cr)
+    }
+
+    fn begin_tx(&mut self) -> Result<(), KoalaError> {
+        (**self).begin_tx()
+    }
+
+    fn commit_tx(&mut self) -> Result<(), KoalaError> {
+        (**self).commit_tx()
+    }
+
+    fn rollback_tx(&mut self) -> Result<(), KoalaError> {
+        (**self).rollback_tx()
+    }
+
+    fn commit_batch(&mut self) -> Result<(), KoalaError> {
+        (**self).commit_batch()
+    }
+
+    fn keeps_checkpoints(&self) -> bool {
+        (**self).keeps_checkpoints()
+    }
+
+    fn load_checkpoint(&self) -> Result<Option<Checkpoint>, KoalaError> {
+        (**self).load_checkpoint()
+    }
+
+    fn save_checkpoint(
+        &mut self,
+        checkpoint: &Checkpoint,
+    ) -> Result<(), KoalaError> {
+        (**self).save_checkpoint(checkpoint)
+    }
 }
@@ -1,18 +1,223 @@
+use crate::engine::{DisputeState, TransactionKind};
+use crate::money::Money;
+use crate::rejection::Rejection;
 use thiserror::Error;
+
+/// Everything that can go wrong while processing. Refusals of a
+/// single transaction carry its client and tx id, along with the
+/// values the engine expected and found where there are any.
 #[derive(Error, Debug)]
 pub enum KoalaError {
-    #[error("invalid input received")]
-    InputError,
-    #[error("balance not enough for transaction")]
-    BalanceError,
-    #[error("partener information error")]
-    PartnerError,
-    #[error("account is frozen")]
-    AccountLockedError,
-    #[error("other error")]
-    Other(#[from] csv::Error),
-    #[error("io error")]
+    #[error("invalid input {0:?}")]
+    InputError(String),
+    #[error("client {client} has {available} available, tx {tx} needs {requested}")]
+    BalanceError {
+        client: u16,
+        tx: String,
+        available: Money,
+        requested: Money,
+    },
+    #[error("tx {tx} of client {client} has amount {amount}, expected {expected}")]
+    AmountError {
+        client: u16,
+        tx: String,
+        amount: Money,
+        expected: &'static str,
+    },
+    #[error("tx {tx} referenced by client {client} does not exist")]
+    MissingTransactionError { client: u16, tx: String },
+    #[error("account of client {client} is frozen, tx {tx} refused")]
+    AccountLockedError { client: u16, tx: String },
+    #[error("amount out of range")]
+    OverflowError,
+    #[error("client {client} is quarantined after an earlier refused transaction, tx {tx} held back")]
+    QuarantinedError { client: u16, tx: String },
+    #[error("can't {action} tx {tx} of client {client}, it is {state}")]
+    DisputeStateError {
+        client: u16,
+        tx: String,
+        state: DisputeState,
+        action: TransactionKind,
+    },
+    #[error("tx {tx} belongs to client {owner}, not client {client}")]
+    ClientMismatchError { client: u16, tx: String, owner: u16 },
+    #[error("tx {tx} of client {client} reuses the id of an earlier transaction")]
+    DuplicateTransactionError { client: u16, tx: String },
+    #[error("tx {tx} of client {client} is a withdrawal, which can't be disputed")]
+    WithdrawalDisputeError { client: u16, tx: String },
+    #[error("tx {tx} of client {client} is a {kind}, only deposits and withdrawals can be disputed")]
+    NotDisputableError {
+        client: u16,
+        tx: String,
+        kind: TransactionKind,
+    },
+    #[error("{kind} tx {tx} of client {client} is administrative and only accepted from an operator")]
+    UnauthorizedError {
+        client: u16,
+        tx: String,
+        kind: TransactionKind,
+    },
+    #[error("{kind} tx {tx} of client {client} needs an amount")]
+    AmountMissingError {
+        client: u16,
+        tx: String,
+        kind: TransactionKind,
+    },
+    #[error("{kind} tx {tx} of client {client} can't have an amount")]
+    AmountNotAllowedError {
+        client: u16,
+        tx: String,
+        kind: TransactionKind,
+    },
+    #[error("{kind} tx {tx} of client {client} needs a reason")]
+    ReasonMissingError {
+        client: u16,
+        tx: String,
+        kind: TransactionKind,
+    },
+    #[error("input file hash {actual} does not match the checkpoint's {expected}, it can't be resumed")]
+    CheckpointError { expected: String, actual: String },
+    #[error("database schema error: {0}")]
+    SchemaError(String),
+    #[error("line {line}: {source}")]
+    LineError {
+        line: u64,
+        #[source]
+        source: Box<KoalaError>,
+    },
+    /// A refused row which stopped a run under the abort policy
+    #[error("line {}: {}", .0.line, .0.reason)]
+    AbortedError(Box<Rejection>),
+    #[error("{name}: {error}")]
+    SourceError {
+        name: String,
+        #[source]
+        error: Box<KoalaError>,
+    },
+    #[error("csv error: {0}")]
+    CsvError(#[from] csv::Error),
+    #[error("io error: {0}")]
     IO(#[from] std::io::Error),
-    #[error("db error")]
-    DB(#[from] rusqlite::Error)
-}
\ No newline at end of file
+    #[error("db error: {0}")]
+    DB(#[from] rusqlite::Error),
+}
+
+impl KoalaError {
+    /// Stable, machine readable code for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            KoalaError::InputError(_) => "invalid_input",
+            KoalaError::BalanceError { .. } => "insufficient_funds",
+            KoalaError::AmountError { .. } => "invalid_amount",
+            KoalaError::MissingTransactionError { .. } => "missing_transaction",
+            KoalaError::AccountLockedError { .. } => "account_locked",
+            KoalaError::OverflowError => "amount_out_of_range",
+            KoalaError::QuarantinedError { .. } => "client_quarantined",
+            KoalaError::DisputeStateError { .. } => "invalid_dispute_state",
+            KoalaError::ClientMismatchError { .. } => "client_mismatch",
+            KoalaError::DuplicateTransactionError { .. } => {
+                "duplicate_transaction"
+            }
+            KoalaError::WithdrawalDisputeError { .. } => "withdrawal_dispute",
+            KoalaError::NotDisputableError { .. } => "not_disputable",
+            KoalaError::UnauthorizedError { .. } => "unauthorized",
+            KoalaError::AmountMissingError { .. } => "amount_missing",
+            KoalaError::AmountNotAllowedError { .. } => "amount_not_allowed",
+            KoalaError::ReasonMissingError { .. } => "reason_missing",
+            KoalaError::CheckpointError { .. } => "checkpoint_mismatch",
+            KoalaError::SchemaError(_) => "schema_error",
+            KoalaError::LineError { source, .. } => source.code(),
+            KoalaError::AbortedError(rejection) => rejection.reason.code(),
+            KoalaError::SourceError { error, .. } => error.code(),
+            KoalaError::CsvError(err) => match err.kind() {
+                csv::ErrorKind::Deserialize { .. } => "malformed_row",
+                _ => "csv_error",
+            },
+            KoalaError::IO(_) => "io_error",
+            KoalaError::DB(_) => "db_error",
+        }
+    }
+
+    /// Whether the error is about the run rather than a single
+    /// transaction, so processing can't carry on after it
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            KoalaError::IO(_)
+            | KoalaError::DB(_)
+            | KoalaError::CheckpointError { .. }
+            | KoalaError::SchemaError(_) => true,
+            KoalaError::CsvError(err) => {
+                !matches!(err.kind(), csv::ErrorKind::Deserialize { .. })
+            }
+            KoalaError::LineError { source, .. } => source.is_fatal(),
+            KoalaError::SourceError { error, .. } => error.is_fatal(),
+            _ => false,
+        }
+    }
+
+    /// Attaches the input line the error happened on
+    pub fn at_line(self, line: u64) -> KoalaError {
+        KoalaError::LineError {
+            line,
+            source: Box::new(self),
+        }
+    }
+
+    /// Returns the refused row which stopped the run, when the
+    /// error is a refusal under the abort policy
+    pub fn aborted_row(&self) -> Option<&Rejection> {
+        match self {
+            KoalaError::AbortedError(rejection) => Some(rejection),
+            KoalaError::SourceError { error, .. } => error.aborted_row(),
+            _ => None,
+        }
+    }
+
+    /// Attaches the name of the input source the error came from
+    pub fn in_source(self, name: &str) -> KoalaError {
+        KoalaError::SourceError {
+            name: name.to_string(),
+            error: Box::new(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_and_code() {
+        let err = KoalaError::BalanceError {
+            client: 7,
+            tx: "12".to_string(),
+            available: "1.5".parse().unwrap(),
+            requested: "2".parse().unwrap(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "client 7 has 1.5000 available, tx 12 needs 2.0000"
+        );
+        assert_eq!(err.code(), "insufficient_funds");
+
+        // The line is added to the message, the code stays the same
+        let err = err.at_line(3);
+        assert_eq!(
+            err.to_string(),
+            "line 3: client 7 has 1.5000 available, tx 12 needs 2.0000"
+        );
+        assert_eq!(err.code(), "insufficient_funds");
+        assert!(!err.is_fatal());
+
+        let err = KoalaError::DisputeStateError {
+            client: 1,
+            tx: "4".to_string(),
+            state: DisputeState::ChargedBack,
+            action: TransactionKind::Resolve,
+        };
+        assert_eq!(
+            err.to_string(),
+            "can't resolve tx 4 of client 1, it is chargedback"
+        );
+    }
+}
@@ -0,0 +1,312 @@
+use crate::engine::{Engine, Transaction};
+use crate::errors::KoalaError;
+use crate::summary::RunSummary;
+use crate::{ProcessOptions, Run};
+use csv::{ReaderBuilder, StringRecord, Trim};
+use std::fs::File;
+use std::io::Read;
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, SyncSender};
+use std::thread;
+use tracing::{debug, info};
+
+/// Rows read ahead of the engine, over every source. Once the
+/// channel is full the readers wait, so a busy engine pushes back
+/// on the senders instead of filling up memory.
+const CHANNEL_SIZE: usize = 1024;
+
+/// A stream of transactions, csv with a header row
+pub struct Source {
+    name: String,
+    reader: Box<dyn Read + Send>,
+    /// Administrative transactions are only accepted
+    /// from the sources of operators
+    operator: bool,
+}
+
+impl Source {
+    /// Returns a source reading from any reader
+    pub fn new(name: impl Into<String>, reader: impl Read + Send + 'static) -> Source {
+        Source {
+            name: name.into(),
+            reader: Box::new(reader),
+            operator: false,
+        }
+    }
+
+    /// Opens a source given as a file path, `-` for stdin,
+    /// `tcp://host:port` or `unix:path` for a socket
+    pub fn open(spec: &str) -> Result<Source, KoalaError> {
+        let reader: Box<dyn Read + Send> = if spec == "-" {
+            Box::new(std::io::stdin())
+        } else if let Some(addr) = spec.strip_prefix("tcp://") {
+            let stream = TcpStream::connect(addr)
+                .map_err(|err| KoalaError::from(err).in_source(spec))?;
+            Box::new(stream)
+        } else if let Some(path) = spec.strip_prefix("unix:") {
+            open_unix(path).map_err(|err| err.in_source(spec))?
+        } else {
+            let file = File::open(spec)
+                .map_err(|err| KoalaError::from(err).in_source(spec))?;
+            Box::new(file)
+        };
+
+        Ok(Source {
+            name: spec.to_string(),
+            reader,
+            operator: false,
+        })
+    }
+
+    /// Marks the source as one of an operator, whose
+    /// administrative transactions are accepted
+    pub fn operator(mut self) -> Source {
+        self.operator = true;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+}
+
+#[cfg(unix)]
+fn open_unix(path: &str) -> Result<Box<dyn Read + Send>, KoalaError> {
+    Ok(Box::new(UnixStream::connect(path)?))
+}
+
+#[cfg(not(unix))]
+fn open_unix(path: &str) -> Result<Box<dyn Read + Send>, KoalaError> {
+    Err(KoalaError::InputError(format!("unix:{}", path)))
+}
+
+/// What a reader thread hands to the engine
+enum Message {
+    Row {
+        source: usize,
+        line: u64,
+        row: StringRecord,
+        record: Result<Transaction, csv::Error>,
+    },
+    /// The source can't be read any further
+    Failed { source: usize, error: csv::Error },
+}
+
+/// Processes the rows of every source as they arrive. The rows of a
+/// source are applied in their order, rows of different sources are
+/// interleaved as they come in. Refused rows are handled like those
+/// of `process_txs`, their line is the line in their own source.
+/// Administrative transactions are refused, unless their source is
+/// an operator's. Sources can't be resumed, so no checkpoint is saved.
+pub fn process_sources(
+    sources: Vec<Source>,
+    processor: impl Engine,
+    options: &ProcessOptions,
+) -> Result<RunSummary, KoalaError> {
+    let (sender, receiver) = mpsc::sync_channel(CHANNEL_SIZE);
+    let mut names = Vec::new();
+    let mut operators = Vec::new();
+    for (index, source) in sources.into_iter().enumerate() {
+        info!(
+            source = source.name.as_str(),
+            operator = source.operator,
+            "reading"
+        );
+        names.push(source.name);
+        operators.push(source.operator);
+        let sender = sender.clone();
+        thread::spawn(move || read_source(index, source.reader, sender));
+    }
+    drop(sender);
+
+    // Readers are never joined, one waiting on a quiet socket must
+    // not hold up a run that stopped
+    let mut run = Run::new(processor, options, None);
+    for message in receiver {
+        match message {
+            Message::Row {
+                source,
+                line,
+                row,
+                record,
+            } => {
+                let name = names[source].as_str();
+                let operator = operators[source];
+                run.handle_row(Some(name), operator, line, row, record)
+                    .map_err(|err| err.in_source(name))?;
+            }
+            Message::Failed { source, error } => {
+                return Err(KoalaError::from(error).in_source(&names[source]));
+            }
+        }
+    }
+
+    run.finish()
+}
+
+/// Reads the rows of a source until it ends, fails or
+/// the engine hangs up
+fn read_source(
+    source: usize,
+    reader: Box<dyn Read + Send>,
+    sender: SyncSender<Message>,
+) {
+    let mut rdr = ReaderBuilder::new().trim(Trim::All).from_reader(reader);
+    let headers = match rdr.headers() {
+        Ok(headers) => headers.clone(),
+        Err(error) => {
+            sender.send(Message::Failed { source, error }).ok();
+            return;
+        }
+    };
+
+    for result in rdr.into_records() {
+        let (message, failed) = match result {
+            Ok(row) => {
+                let line = row.position().map_or(0, |pos| pos.line());
+                let record = row.deserialize(Some(&headers));
+                let message = Message::Row {
+                    source,
+                    line,
+                    row,
+                    record,
+                };
+                (message, false)
+            }
+            Err(error) => (Message::Failed { source, error }, true),
+        };
+        if sender.send(message).is_err() || failed {
+            return;
+        }
+    }
+    debug!(source, "source finished");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemoryProcessor;
+    use rand::{thread_rng, Rng};
+    use std::io::{Cursor, Write};
+    use std::net::TcpListener;
+
+    const TMP_OUT_FILE: &str = "data/tmp/tmp_ingest_out_test.csv";
+    const TMP_SOCKET: &str = "data/tmp/tmp_ingest.sock";
+
+    /// Deposits and withdraws the same amount over and over, any
+    /// reordering within the source refuses a withdrawal
+    fn back_and_forth(client: u16, first_tx: u32, rows: u32) -> String {
+        let mut csv = String::from("type, client, tx, amount\n");
+        for tx in first_tx..first_tx + rows {
+            let kind = if tx % 2 == 0 { "deposit" } else { "withdrawal" };
+            csv.push_str(&format!("{}, {}, {}, 1.5\n", kind, client, tx));
+        }
+        csv
+    }
+
+    #[test]
+    fn test_sources_keep_their_order() {
+        let rnum: u32 = thread_rng().gen();
+        let out_file = format!("{}_{}", TMP_OUT_FILE, rnum);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .write_all(back_and_forth(3, 20_000, 2000).as_bytes())
+                .unwrap();
+        });
+
+        let mut sources = vec![
+            Source::new("one", Cursor::new(back_and_forth(1, 0, 2000))),
+            Source::new("two", Cursor::new(back_and_forth(2, 10_000, 2000))),
+            Source::open(&format!("tcp://{}", addr)).unwrap(),
+        ];
+
+        #[cfg(unix)]
+        let socket = {
+            let path = format!("{}_{}", TMP_SOCKET, rnum);
+            let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+            thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                stream
+                    .write_all(back_and_forth(4, 30_000, 2000).as_bytes())
+                    .unwrap();
+            });
+            sources.push(Source::open(&format!("unix:{}", path)).unwrap());
+            path
+        };
+
+        let writer = File::create(&out_file).unwrap();
+        let processor = MemoryProcessor::new(writer).unwrap();
+        let rejections =
+            process_sources(sources, processor, &ProcessOptions::default())
+                .unwrap()
+                .rejections;
+        server.join().unwrap();
+        assert!(rejections.is_empty(), "{:?}", rejections);
+
+        let output = std::fs::read_to_string(&out_file).unwrap();
+        let clients = if cfg!(unix) { 4 } else { 3 };
+        assert_eq!(output.lines().count(), clients + 1);
+        for line in output.lines().skip(1) {
+            assert!(line.ends_with(",0.0000,0.0000,0.0000,false"), "{}", line);
+        }
+
+        std::fs::remove_file(out_file).unwrap();
+        #[cfg(unix)]
+        std::fs::remove_file(socket).unwrap();
+    }
+
+    #[test]
+    fn test_rejections_name_their_source() {
+        let sources = vec![
+            Source::new("good", Cursor::new(back_and_forth(1, 0, 10))),
+            Source::new(
+                "bad",
+                Cursor::new("type, client, tx, amount\nwithdrawal, 2, 99, 1.0\n"),
+            ),
+        ];
+        let processor = MemoryProcessor::new(std::io::sink()).unwrap();
+        let rejections =
+            process_sources(sources, processor, &ProcessOptions::default())
+                .unwrap()
+                .rejections;
+
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].source.as_deref(), Some("bad"));
+        assert_eq!(rejections[0].line, 2);
+    }
+
+    /// Fails like a dropped connection
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("connection reset"))
+        }
+    }
+
+    #[test]
+    fn test_failed_source_stops_the_run() {
+        let sources = vec![
+            Source::new("good", Cursor::new(back_and_forth(1, 0, 10))),
+            Source::new("broken", Broken),
+        ];
+        let processor = MemoryProcessor::new(std::io::sink()).unwrap();
+        let result =
+            process_sources(sources, processor, &ProcessOptions::default());
+
+        match result {
+            Err(KoalaError::SourceError { name, error }) => {
+                assert_eq!(name, "broken");
+                assert!(error.is_fatal());
+            }
+            _ => panic!("expected the broken source to stop the run"),
+        }
+    }
+}
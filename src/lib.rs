@@ -2,43 +2,433 @@ pub mod cs;
 pub mod db;
 pub mod engine;
 pub mod errors;
+pub mod ingest;
+pub mod mem;
+pub mod money;
+pub mod pipeline;
+pub mod rejection;
+pub mod summary;
 
-use csv::{ReaderBuilder, Trim};
-use engine::{Engine, Transaction};
+use csv::{ReaderBuilder, StringRecord, Trim};
+use engine::{Checkpoint, Effect, Engine, Transaction, TransactionKind};
 use errors::KoalaError;
+use rejection::Rejection;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::time::Instant;
+use summary::{RunSummary, Tally};
+use tracing::{debug, field, info, info_span, trace};
 
-/// Read the transaction data and call the
-/// appropriate transaction function
+/// What happens when the engine refuses a transaction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stop processing at the first refused transaction
+    Abort,
+    /// Record the refused transaction and carry on
+    #[default]
+    Skip,
+    /// Record the refused transaction and hold back every
+    /// later transaction of the same client, so its balance
+    /// stays as it was before the first refusal
+    Quarantine,
+}
+
+/// Rows committed together by processors that support it
+pub const DEFAULT_BATCH_SIZE: usize = 10_000;
+
+/// Options for a processing run
+#[derive(Debug, Clone)]
+pub struct ProcessOptions {
+    pub error_policy: ErrorPolicy,
+    /// Skip the rows before the checkpoint saved by an earlier,
+    /// interrupted run of the same input file
+    pub resume: bool,
+    /// Rows per durable commit. Every row is applied atomically,
+    /// a crash loses at most the rows of the open batch, which a
+    /// resumed run then applies again.
+    pub batch_size: usize,
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        ProcessOptions {
+            error_policy: ErrorPolicy::default(),
+            resume: false,
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+}
+
+/// Read the transaction data and call the appropriate
+/// transaction function. Refused transactions are handled
+/// according to the error policy and returned with the
+/// summary of the run, io and db errors always stop the run.
+/// The file is customer traffic, so administrative transactions
+/// are refused, they are only taken from operator sources.
+/// When the processor keeps checkpoints, the file's checkpoint
+/// is saved along the way, so an interrupted run can be resumed.
+/// A resumed run still holds back the clients quarantined before
+/// the checkpoint.
+/// Pass `&mut processor` to keep the processor after the run.
 pub fn process_txs(
     input_file: String,
-    mut processor: impl Engine,
-) -> Result<(), KoalaError> {
-    let mut counter = 0;
+    processor: impl Engine,
+    options: &ProcessOptions,
+) -> Result<RunSummary, KoalaError> {
+    // Hashing reads the whole file, only do it when it is kept
+    let checkpoint = match processor.keeps_checkpoints() {
+        true => Some(Checkpoint {
+            input_hash: file_hash(&input_file)?,
+            line: 0,
+            quarantined: BTreeSet::new(),
+        }),
+        false => None,
+    };
+    let checkpoint = match (processor.load_checkpoint()?, checkpoint) {
+        (Some(saved), Some(checkpoint)) if options.resume => {
+            if saved.input_hash != checkpoint.input_hash {
+                return Err(KoalaError::CheckpointError {
+                    expected: saved.input_hash,
+                    actual: checkpoint.input_hash,
+                });
+            }
+            info!(line = saved.line, "resuming after the checkpoint");
+            Some(saved)
+        }
+        (_, checkpoint) => checkpoint,
+    };
+    let resume_after =
+        checkpoint.as_ref().map_or(0, |checkpoint| checkpoint.line);
+
+    let file = File::open(input_file)?;
+    let run = Run::new(processor, options, checkpoint);
+    process_csv(file, run, resume_after)
+}
+
+/// Processes csv transaction data with a header row, like
+/// `process_txs` does with a file. A reader can't be resumed,
+/// so no checkpoint is saved.
+pub fn process_reader<R: Read>(
+    reader: R,
+    processor: impl Engine,
+    options: &ProcessOptions,
+) -> Result<RunSummary, KoalaError> {
+    process_csv(reader, Run::new(processor, options, None), 0)
+}
+
+/// Processes transactions which are already parsed. The line
+/// of a rejection is the position of the transaction, from 1,
+/// and its row is the transaction written out as csv fields.
+pub fn process_iter(
+    transactions: impl IntoIterator<Item = Transaction>,
+    processor: impl Engine,
+    options: &ProcessOptions,
+) -> Result<RunSummary, KoalaError> {
+    let mut run = Run::new(processor, options, None);
+    for (line, record) in (1..).zip(transactions) {
+        let row = StringRecord::from(vec![
+            record.tx_type.to_string(),
+            record.client.to_string(),
+            record.tx.clone(),
+            record.amount.map_or(String::new(), |amount| amount.to_string()),
+            record.reason.clone().unwrap_or_default(),
+        ]);
+        run.handle_row(None, false, line, row, Ok(record))?;
+    }
+
+    run.finish()
+}
+
+/// Feeds every row after `resume_after` to the run
+fn process_csv<R: Read, E: Engine>(
+    reader: R,
+    mut run: Run<E>,
+    resume_after: u64,
+) -> Result<RunSummary, KoalaError> {
+    let mut rdr = ReaderBuilder::new().trim(Trim::All).from_reader(reader);
+    let headers = rdr.headers()?.clone();
+
+    for result in rdr.records() {
+        let row = result?;
+        let line = row.position().map_or(0, |pos| pos.line());
+        if line <= resume_after {
+            continue;
+        }
+        let record = row.deserialize(Some(&headers));
+        run.handle_row(None, false, line, row, record)?;
+    }
+
+    run.finish()
+}
+
+/// A processing run, which applies rows one at a time and
+/// keeps count of what they did
+struct Run<'a, E: Engine> {
+    processor: E,
+    options: &'a ProcessOptions,
+    /// Saved after every row, when the input can be resumed
+    checkpoint: Option<Checkpoint>,
+    /// Taken over from the checkpoint, when resuming
+    quarantined: HashSet<u16>,
+    tally: Tally,
+    rows: usize,
+    started: Instant,
+}
+
+impl<'a, E: Engine> Run<'a, E> {
+    fn new(
+        processor: E,
+        options: &'a ProcessOptions,
+        checkpoint: Option<Checkpoint>,
+    ) -> Self {
+        Run {
+            processor,
+            options,
+            quarantined: checkpoint
+                .iter()
+                .flat_map(|checkpoint| checkpoint.quarantined.iter().copied())
+                .collect(),
+            checkpoint,
+            tally: Tally::default(),
+            rows: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Applies a row read from the named source, or the input file.
+    /// Only rows of an operator may be administrative. Refusals are
+    /// handled according to the error policy, an error means the run
+    /// has to stop.
+    fn handle_row(
+        &mut self,
+        source: Option<&str>,
+        operator: bool,
+        line: u64,
+        row: StringRecord,
+        record: Result<Transaction, csv::Error>,
+    ) -> Result<(), KoalaError> {
+        let span = info_span!(
+            "tx",
+            source,
+            line,
+            client = field::Empty,
+            tx = field::Empty,
+            kind = field::Empty,
+        );
+        let _enter = span.enter();
+
+        let (client, tx, counted, result) = match record {
+            Ok(record) => {
+                let (client, tx) = (record.client, record.tx.clone());
+                span.record("client", client);
+                span.record("tx", tx.as_str());
+                span.record("kind", record.tx_type.as_str());
+                let counted = self
+                    .tally
+                    .read(&self.processor, &record)
+                    .map_err(|err| err.at_line(line))?;
+                let result = process_record(
+                    &mut self.processor,
+                    record,
+                    operator,
+                    &self.quarantined,
+                );
+                (Some(client), Some(tx), Some(counted), result)
+            }
+            Err(err) => (None, None, None, Err(KoalaError::from(err))),
+        };
+
+        self.rows += 1;
+        match result {
+            Ok(Effect::Applied) => {
+                trace!("processed");
+                if let Some(counted) = counted {
+                    self.tally
+                        .applied(&self.processor, counted)
+                        .map_err(|err| err.at_line(line))?;
+                }
+            }
+            Ok(Effect::Ignored) => {
+                trace!("ignored");
+                self.tally.replayed();
+            }
+            // Anything not yet committed is lost with the run
+            Err(err) if err.is_fatal() => return Err(err.at_line(line)),
+            Err(reason) => {
+                let rejection = Rejection {
+                    source: source.map(String::from),
+                    line,
+                    row,
+                    client,
+                    tx,
+                    reason,
+                };
+                // The refused row goes back with the error
+                if self.options.error_policy == ErrorPolicy::Abort {
+                    self.processor.commit_batch()?;
+                    return Err(KoalaError::AbortedError(Box::new(rejection)));
+                }
+                let reason = &rejection.reason;
+                info!(code = reason.code(), "refused: {}", reason);
+                if self.options.error_policy == ErrorPolicy::Quarantine {
+                    self.quarantined.extend(client);
+                    if let Some(checkpoint) = &mut self.checkpoint {
+                        checkpoint.quarantined.extend(client);
+                    }
+                }
+                match client {
+                    Some(_) => self.tally.refused(rejection),
+                    None => self.tally.malformed(rejection),
+                }
+            }
+        }
+
+        if let Some(checkpoint) = &mut self.checkpoint {
+            checkpoint.line = line;
+            self.processor.save_checkpoint(checkpoint)?;
+        }
+        if self.rows.is_multiple_of(self.options.batch_size.max(1)) {
+            self.processor.commit_batch()?;
+        }
+        Ok(())
+    }
+
+    /// Commits what is left and lets the processor finish
+    fn finish(mut self) -> Result<RunSummary, KoalaError> {
+        self.processor.commit_batch()?;
+        let mut summary = self.tally.finish(&self.processor)?;
+        self.processor.finish()?;
+        summary.timed(self.started.elapsed());
+        debug!(
+            rows = self.rows,
+            refused = summary.rejections.len(),
+            "finished"
+        );
+
+        Ok(summary)
+    }
+}
+
+/// Applies a record as one atomic unit of work, unless
+/// its client is quarantined
+fn process_record(
+    processor: &mut impl Engine,
+    record: Transaction,
+    operator: bool,
+    quarantined: &HashSet<u16>,
+) -> Result<Effect, KoalaError> {
+    if quarantined.contains(&record.client) {
+        return Err(KoalaError::QuarantinedError {
+            client: record.client,
+            tx: record.tx,
+        });
+    }
+
+    processor.begin_tx()?;
+    let result = apply(processor, record, operator);
+    match &result {
+        Ok(_) => processor.commit_tx()?,
+        Err(err) if !err.is_fatal() => processor.rollback_tx()?,
+        Err(_) => {}
+    }
+    result
+}
+
+/// Returns the sha-256 of the file, in hex
+fn file_hash(path: &str) -> Result<String, KoalaError> {
+    let mut hasher = Sha256::new();
+    let mut file = File::open(path)?;
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Calls the transaction function for the record type,
+/// administrative ones only for a record of an operator
+fn apply(
+    processor: &mut impl Engine,
+    record: Transaction,
+    operator: bool,
+) -> Result<Effect, KoalaError> {
+    if record.tx_type.is_admin() && !operator {
+        return Err(KoalaError::UnauthorizedError {
+            client: record.client,
+            tx: record.tx,
+            kind: record.tx_type,
+        });
+    }
+
+    match record.tx_type {
+        TransactionKind::Deposit => processor.deposit_tx(record),
+        TransactionKind::Withdrawal => processor.withdrawal_tx(record),
+        TransactionKind::Dispute => processor.dispute_tx(&record),
+        TransactionKind::Resolve => processor.resolve_tx(&record),
+        TransactionKind::Chargeback => processor.chargeback_tx(&record),
+        TransactionKind::Freeze => processor.freeze_tx(record),
+        TransactionKind::Unlock => processor.unlock_tx(record),
+        TransactionKind::Adjustment => processor.adjustment_tx(record),
+    }
+}
+
+/// Checks every row of the transaction data without
+/// processing it, returning the line number and problem
+/// of each invalid row
+pub fn validate_txs(input_file: String) -> Result<Vec<(u64, String)>, KoalaError> {
+    let mut problems = Vec::new();
     let mut rdr =
         ReaderBuilder::new().trim(Trim::All).from_path(input_file)?;
+    let headers = rdr.headers()?.clone();
 
-    for result in rdr.deserialize() {
-        let record: Transaction = result?;
-        let tx_type = record.tx_type.as_str();
+    for result in rdr.records() {
+        let row = result?;
+        let line = row.position().map_or(0, |pos| pos.line());
 
-        // Print some info
-        let amount = match &record.amount {
-            Some(amt) => amt,
-            None => &0.0,
-        };
-        println!("Transaction - Amount: {} Id: {} Client: {}", amount, &record.tx, &record.client);
-
-        match tx_type {
-            "deposit" => processor.deposit_tx(record)?,
-            "withdrawal" => processor.withdrawal_tx(record)?,
-            "dispute" => processor.dispute_tx(&record)?,
-            "resolve" => processor.resolve_tx(&record)?,
-            "chargeback" => processor.chargeback_tx(&record)?,
-            &_ => Err(KoalaError::InputError)?,
+        let record: Transaction = match row.deserialize(Some(&headers)) {
+            Ok(record) => record,
+            Err(err) => {
+                problems.push((line, err.to_string()));
+                continue;
+            }
         };
-        counter += 1;
-        println!("Processed {} transactions", counter);
+
+        // An input file is never an operator source, so its
+        // administrative rows are refused as in process_txs
+        if record.tx_type.is_admin() {
+            let err = KoalaError::UnauthorizedError {
+                client: record.client,
+                tx: record.tx,
+                kind: record.tx_type,
+            };
+            problems.push((line, err.to_string()));
+            continue;
+        }
+
+        if let Err(err) = record.check_amount() {
+            problems.push((line, err.to_string()));
+            continue;
+        }
+
+        let amount = record.amount.unwrap_or_default();
+        if matches!(
+            record.tx_type,
+            TransactionKind::Deposit | TransactionKind::Withdrawal
+        ) && !amount.is_positive()
+        {
+            problems.push((line, "amount must be positive".to_string()));
+        }
     }
 
-    Ok(())
+    Ok(problems)
 }
@@ -1,68 +1,430 @@
+mod cli;
+
+use clap::Parser;
+use cli::{
+    Backend, Cli, Command, Duplicates, LogFormat, OnError, ProcessArgs, RejectsFormat, ReplayArgs,
+    ReportArgs, SummaryFormat, ValidateArgs, WithdrawalDisputes, DEFAULT_CONFIG, EXIT_FAILURE, EXIT_MISMATCH, EXIT_SUCCESS, EXIT_USAGE,
+};
 use config::Config;
-use koala_transaction_engine::cs::CSVProcessor;
-use koala_transaction_engine::db::DBProcessor;
+use koala_transaction_engine::cs::{self, CSVProcessor};
+use koala_transaction_engine::db::{self, DBProcessor};
+use koala_transaction_engine::engine::{ClientRecord, Engine, EngineConfig};
 use koala_transaction_engine::errors::KoalaError;
-use koala_transaction_engine::process_txs;
-use std::env::args;
+use koala_transaction_engine::ingest::{process_sources, Source};
+use koala_transaction_engine::mem::MemoryProcessor;
+use koala_transaction_engine::pipeline::process_txs_sharded;
+use koala_transaction_engine::rejection::write_report;
+use koala_transaction_engine::summary::RunSummary;
+use koala_transaction_engine::{
+    process_txs, validate_txs, ProcessOptions, DEFAULT_BATCH_SIZE,
+};
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs;
+use std::io::IsTerminal;
+use std::process::ExitCode;
+use tracing::{debug, info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// Starts the processing, parses the command
+/// line and runs the requested subcommand
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli) {
+        Ok(code) => ExitCode::from(code),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+/// Runs the subcommand, returning the exit code
+fn run(cli: Cli) -> Result<u8, Box<dyn Error>> {
+    if let Err(err) = init_logging(&cli.log, cli.log_format) {
+        eprintln!("Invalid --log: {}", err);
+        return Ok(EXIT_USAGE);
+    }
+
+    // A bare input file streams the account table to stdout
+    let command = match (cli.command, cli.input) {
+        (Some(command), _) => command,
+        (None, Some(input)) => Command::Process(ProcessArgs {
+            backend: Backend::Memory,
+            on_error: OnError::Skip,
+            duplicates: Duplicates::Reject,
+            withdrawal_disputes: WithdrawalDisputes::Reject,
+            rejects: None,
+            rejects_format: RejectsFormat::Csv,
+            input: Some(input),
+            sources: Vec::new(),
+            operators: Vec::new(),
+            output: Some("-".to_string()),
+            tmp_file: None,
+            db_file: None,
+            persist: false,
+            resume: false,
+            batch_size: DEFAULT_BATCH_SIZE,
+            threads: 1,
+            summary: None,
+        }),
+        (None, None) => {
+            eprintln!("Nothing to do, see `koala --help`");
+            return Ok(EXIT_USAGE);
+        }
+    };
 
-/// Starts the processing, checks for the
-/// method provided by args and call the
-/// appropriate processing engine
-fn main() -> Result<(), Box<dyn Error>> {
     // Get the config
-    let conf = Config::builder()
-        .add_source(config::File::with_name("proj-config.toml"))
-        .build()
-        .unwrap();
-    let input_file = conf.get_string("input_file").unwrap();
-    let output_file = conf.get_string("output_file").unwrap();
-
-    // Get the method for processing
-    let input = args().nth(1).unwrap();
-    let method = input.as_str();
-
-    // Check if we have the method preference from the user
-    if !(method == "csv" || method == "db") {
-        println!("{:?}", method);
-        panic!("Either use 'csv' or 'db' for method");
-    }
-
-    // Check what method is given, and process with appropriate data
-    match method {
-        "csv" => {
-            let tmp_file = conf.get_string("tmp_csv_file").unwrap();
-            reset_file(output_file.as_str())?;
-            reset_file(&tmp_file.as_str())?;
-            let proc = CSVProcessor::new(tmp_file, output_file)?;
-            process_txs(input_file, proc)?;
+    let conf = match load_config(cli.config.as_deref()) {
+        Ok(conf) => conf,
+        Err(err) => {
+            eprintln!("Unable to load config: {}", err);
+            return Ok(EXIT_USAGE);
         }
-        "db" => {
-            let db_file = conf.get_string("tmp_db_file").unwrap();
-            reset_file(db_file.as_str())?;
-            let proc = DBProcessor::new(db_file)?;
-            process_txs(input_file, proc)?;
+    };
+
+    let result = match command {
+        Command::Process(args) => process(&conf, args),
+        Command::Report(args) => report(&conf, args),
+        Command::Replay(args) => replay(&conf, args),
+        Command::Validate(args) => validate(&conf, args),
+    };
+
+    match result {
+        Err(CommandError::Usage(msg)) => {
+            eprintln!("{}", msg);
+            Ok(EXIT_USAGE)
         }
-        _ => panic!("Something unexpected went wrong"),
+        Err(CommandError::Failed(err)) => Err(err),
+        Ok(code) => Ok(code),
     }
-    println!();
-    println!("Success!!!");
+}
+
+/// Why a subcommand did not run to completion
+enum CommandError {
+    Usage(String),
+    Failed(Box<dyn Error>),
+}
+
+impl<E: Into<Box<dyn Error>>> From<E> for CommandError {
+    fn from(err: E) -> Self {
+        CommandError::Failed(err.into())
+    }
+}
 
+/// Sends log lines to stderr, filtered per module
+fn init_logging(
+    filter: &str,
+    format: LogFormat,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let filter = EnvFilter::try_new(filter)?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.try_init()?,
+        LogFormat::Json => builder.json().try_init()?,
+    }
     Ok(())
 }
 
+/// Loads the config file, with `KOALA_` environment variables
+/// overriding its values. Only an explicitly given file must exist.
+fn load_config(path: Option<&str>) -> Result<Config, config::ConfigError> {
+    let file = match path {
+        Some(path) => config::File::with_name(path),
+        None => config::File::with_name(DEFAULT_CONFIG).required(false),
+    };
+    Config::builder()
+        .add_source(file)
+        .add_source(config::Environment::with_prefix("KOALA"))
+        .build()
+}
+
+/// Returns the flag value, falling back to the config
+fn setting(
+    conf: &Config,
+    flag: Option<String>,
+    key: &str,
+) -> Result<String, CommandError> {
+    if let Some(value) = flag {
+        return Ok(value);
+    }
+    conf.get_string(key).map_err(|_| {
+        CommandError::Usage(format!(
+            "Missing {}, pass it as a flag, set KOALA_{} or add it to the config",
+            key,
+            key.to_uppercase()
+        ))
+    })
+}
+
+/// Processes the input with the chosen backend
+fn process(conf: &Config, args: ProcessArgs) -> Result<u8, CommandError> {
+    let input = if args.sources.is_empty() && args.operators.is_empty() {
+        Input::File(setting(conf, args.input, "input_file")?)
+    } else {
+        if args.resume {
+            return Err(CommandError::Usage(
+                "Streams given with --source or --operator can't be resumed"
+                    .to_string(),
+            ));
+        }
+        let mut sources = if args.sources.is_empty() {
+            // The input file is read as one more customer stream
+            vec![Source::open(&setting(conf, args.input, "input_file")?)?]
+        } else {
+            args.sources
+                .iter()
+                .map(|spec| Source::open(spec))
+                .collect::<Result<Vec<Source>, KoalaError>>()?
+        };
+        for spec in &args.operators {
+            sources.push(Source::open(spec)?.operator());
+        }
+        Input::Sources(sources)
+    };
+    let rejects_file = setting(conf, args.rejects, "rejects_file").ok();
+    let options = ProcessOptions {
+        error_policy: args.on_error.into(),
+        resume: args.resume,
+        batch_size: args.batch_size,
+    };
+    let config = EngineConfig {
+        duplicates: args.duplicates.into(),
+        withdrawal_disputes: args.withdrawal_disputes.into(),
+    };
+
+    let persist = args.persist || args.resume;
+    if persist && args.backend != Backend::Db {
+        return Err(CommandError::Usage(
+            "Only the db backend can --persist or --resume between runs"
+                .to_string(),
+        ));
+    }
+
+    if args.threads > 1 && args.backend != Backend::Memory {
+        return Err(CommandError::Usage(
+            "Only the memory backend can use more than one thread"
+                .to_string(),
+        ));
+    }
+
+    let result = match args.backend {
+        Backend::Csv => {
+            let output_file = setting(conf, args.output, "output_file")?;
+            let proc = if output_file == "-" {
+                CSVProcessor::streaming(std::io::stdout())?
+            } else {
+                let tmp_file = setting(conf, args.tmp_file, "tmp_csv_file")?;
+                reset_file(output_file.as_str())?;
+                reset_file(tmp_file.as_str())?;
+                CSVProcessor::new(tmp_file, output_file)?
+            };
+            process_input(input, proc.with_config(config), &options)
+        }
+        Backend::Db => {
+            let db_file = setting(conf, args.db_file, "tmp_db_file")?;
+            let proc = if persist {
+                DBProcessor::open(db_file)?
+            } else {
+                reset_file(db_file.as_str())?;
+                // A log left by an earlier run doesn't belong to it
+                for suffix in ["-wal", "-shm"] {
+                    let log_file = format!("{}{}", db_file, suffix);
+                    if std::path::Path::new(&log_file).exists() {
+                        fs::remove_file(log_file)?;
+                    }
+                }
+                DBProcessor::new(db_file)?
+            };
+            let proc = proc.with_config(config);
+            process_input(input, proc, &options)
+        }
+        Backend::Memory if args.threads > 1 => {
+            let Input::File(input_file) = input else {
+                return Err(CommandError::Usage(
+                    "More than one thread needs an input file, not streams"
+                        .to_string(),
+                ));
+            };
+            let output_file = setting(conf, args.output, "output_file")?;
+            if output_file == "-" {
+                let writer = std::io::stdout();
+                process_txs_sharded(
+                    input_file,
+                    writer,
+                    config,
+                    &options,
+                    args.threads,
+                )
+            } else {
+                let writer = fs::File::create(output_file.as_str())?;
+                process_txs_sharded(
+                    input_file,
+                    writer,
+                    config,
+                    &options,
+                    args.threads,
+                )
+            }
+        }
+        Backend::Memory => {
+            let output_file = setting(conf, args.output, "output_file")?;
+            if output_file == "-" {
+                let proc = MemoryProcessor::new(std::io::stdout())?
+                    .with_config(config);
+                process_input(input, proc, &options)
+            } else {
+                let writer = fs::File::create(output_file.as_str())?;
+                let proc = MemoryProcessor::new(writer)?.with_config(config);
+                process_input(input, proc, &options)
+            }
+        }
+    };
+    let format = args.rejects_format.into();
+    let summary = match result {
+        Ok(summary) => summary,
+        Err(err) => {
+            // The row which stopped an aborted run is reported too
+            if let (Some(rejects_file), Some(rejection)) =
+                (rejects_file, err.aborted_row())
+            {
+                let writer = fs::File::create(rejects_file.as_str())?;
+                write_report(writer, format, std::slice::from_ref(rejection))?;
+            }
+            return Err(err.into());
+        }
+    };
+    if let Some(rejects_file) = rejects_file {
+        let writer = fs::File::create(rejects_file.as_str())?;
+        write_report(writer, format, &summary.rejections)?;
+    }
+
+    if !summary.rejections.is_empty() {
+        warn!("Refused {} transactions", summary.rejections.len());
+    }
+    match args.summary {
+        Some(SummaryFormat::Table) => summary.write_table(std::io::stderr())?,
+        Some(SummaryFormat::Json) => summary.write_json(std::io::stderr())?,
+        None => {}
+    }
+    info!("Success!!!");
+
+    Ok(EXIT_SUCCESS)
+}
+
+/// Where the transactions of a run come from
+enum Input {
+    File(String),
+    Sources(Vec<Source>),
+}
+
+/// Processes the input file or streams with the processor
+fn process_input(
+    input: Input,
+    processor: impl Engine,
+    options: &ProcessOptions,
+) -> Result<RunSummary, KoalaError> {
+    match input {
+        Input::File(input_file) => process_txs(input_file, processor, options),
+        Input::Sources(sources) => process_sources(sources, processor, options),
+    }
+}
+
+/// Prints the account table of a previous run to stdout
+fn report(conf: &Config, args: ReportArgs) -> Result<u8, CommandError> {
+    let records = match args.backend {
+        Backend::Csv => {
+            let output_file = setting(conf, args.output, "output_file")?;
+            cs::read_client_records(output_file.as_str())?
+        }
+        Backend::Db => {
+            let db_file = setting(conf, args.db_file, "tmp_db_file")?;
+            db::read_client_records(db_file.as_str())?
+        }
+        Backend::Memory => {
+            return Err(CommandError::Usage(
+                "The memory backend keeps nothing to report".to_string(),
+            ));
+        }
+    };
+
+    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    for record in records {
+        wtr.serialize(record)?;
+    }
+    wtr.flush()?;
+
+    Ok(EXIT_SUCCESS)
+}
+
+/// Processes the input in memory and compares the
+/// result with the expected account table
+fn replay(conf: &Config, args: ReplayArgs) -> Result<u8, CommandError> {
+    let input_file = setting(conf, args.input, "input_file")?;
+    let expected_file = setting(conf, args.expected, "output_test_file")?;
+    let replay_file = format!("{}.replay", setting(conf, None, "tmp_csv_file")?);
+
+    let writer = fs::File::create(replay_file.as_str())?;
+    let proc = MemoryProcessor::new(writer)?;
+    process_txs(input_file, proc, &ProcessOptions::default())?;
+    let actual = by_client(cs::read_client_records(replay_file.as_str())?);
+    fs::remove_file(replay_file.as_str())?;
+    let expected = by_client(cs::read_client_records(expected_file.as_str())?);
+
+    let mut mismatches = 0;
+    for id in expected.keys().chain(actual.keys().filter(|id| !expected.contains_key(id))) {
+        let (want, got) = (expected.get(id), actual.get(id));
+        if want != got {
+            eprintln!("Client {} - expected {:?}, got {:?}", id, want, got);
+            mismatches += 1;
+        }
+    }
+
+    if mismatches > 0 {
+        eprintln!("Replay differs for {} clients", mismatches);
+        return Ok(EXIT_MISMATCH);
+    }
+    eprintln!("Replay matches {} clients", expected.len());
+
+    Ok(EXIT_SUCCESS)
+}
+
+/// Reports every invalid row of the input
+fn validate(conf: &Config, args: ValidateArgs) -> Result<u8, CommandError> {
+    let input_file = setting(conf, args.input, "input_file")?;
+    let problems = validate_txs(input_file)?;
+
+    for (line, problem) in &problems {
+        println!("line {}: {}", line, problem);
+    }
+
+    if !problems.is_empty() {
+        eprintln!("Found {} invalid rows", problems.len());
+        return Ok(EXIT_MISMATCH);
+    }
+
+    Ok(EXIT_SUCCESS)
+}
+
+fn by_client(records: Vec<ClientRecord>) -> BTreeMap<u16, ClientRecord> {
+    records.into_iter().map(|record| (record.id, record)).collect()
+}
+
 /// Resets the required data files
 pub fn reset_file(file: &str) -> Result<(), KoalaError> {
-    println!("Resetting file {}", file);
+    debug!("Resetting file {}", file);
     if std::path::Path::new(file).exists() {
-        println!("Removing file: {:?}", file);
+        debug!("Removing file: {:?}", file);
         fs::remove_file(file)?;
     }
-    println!("Creating file: {:?}", file);
+    debug!("Creating file: {:?}", file);
     fs::File::create(file)?;
 
-    println!("Finished resetting file");
+    debug!("Finished resetting file");
 
     Ok(())
 }
@@ -0,0 +1,292 @@
+use crate::engine::{
+    ClientRecord, DisputeState, Engine, EngineConfig, Transaction,
+};
+use crate::errors::KoalaError;
+use crate::money::Money;
+use csv::Writer;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Client records kept in memory, for the processors which
+/// write the account table once at the end
+#[derive(Default)]
+pub(crate) struct RecordStore {
+    records: HashMap<u16, ClientRecord>,
+}
+
+impl RecordStore {
+    /// Returns a client record, an empty one for a new client
+    pub(crate) fn get(&self, client_id: u16) -> ClientRecord {
+        if let Some(record) = self.records.get(&client_id) {
+            return record.clone();
+        }
+
+        ClientRecord {
+            id: client_id,
+            available: Money::ZERO,
+            held: Money::ZERO,
+            total: Money::ZERO,
+            locked: false,
+        }
+    }
+
+    /// Whether the client has a record
+    pub(crate) fn contains(&self, client_id: u16) -> bool {
+        self.records.contains_key(&client_id)
+    }
+
+    /// Updates the client record, with the new record
+    pub(crate) fn update(&mut self, cr: &ClientRecord) {
+        self.records.insert(cr.id, cr.clone());
+    }
+
+    /// Returns the records, sorted by client id
+    pub(crate) fn into_sorted(self) -> Vec<ClientRecord> {
+        let mut records: Vec<ClientRecord> =
+            self.records.into_values().collect();
+        records.sort_by_key(|record| record.id);
+        records
+    }
+
+    /// Writes the account table, sorted by client id, and
+    /// leaves the store empty
+    pub(crate) fn write_table(
+        &mut self,
+        writer: impl Write,
+    ) -> Result<(), KoalaError> {
+        let mut wtr = Writer::from_writer(writer);
+        for record in std::mem::take(self).into_sorted() {
+            wtr.serialize(record)?;
+        }
+        wtr.flush()?;
+
+        Ok(())
+    }
+}
+
+/// The main struct for memory processor, it keeps
+/// transactions and client records in memory and
+/// writes the account table once at the end
+pub struct MemoryProcessor {
+    txs: HashMap<String, Transaction>,
+    records: RecordStore,
+    writer: Box<dyn Write>,
+    config: EngineConfig,
+}
+
+impl MemoryProcessor {
+    /// Returns a new memory processor, which writes the
+    /// account table to the writer when processing finishes
+    pub fn new(
+        writer: impl Write + 'static,
+    ) -> Result<MemoryProcessor, KoalaError> {
+        Ok(MemoryProcessor {
+            txs: HashMap::new(),
+            records: RecordStore::default(),
+            writer: Box::new(writer),
+            config: EngineConfig::default(),
+        })
+    }
+
+    /// Replaces the default engine settings
+    pub fn with_config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self
+    }
+}
+
+impl Engine for MemoryProcessor {
+    fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// Keeps the transaction for later disputes
+    fn store_transaction(&mut self, tx: Transaction) -> Result<(), KoalaError> {
+        self.txs.entry(tx.tx.clone()).or_insert(tx);
+        Ok(())
+    }
+
+    /// Returns a transaction, given a transaction id
+    fn find_original_transaction(
+        &self,
+        tx_id: &str,
+    ) -> Result<Option<Transaction>, KoalaError> {
+        Ok(self.txs.get(tx_id).cloned())
+    }
+
+    /// Updates the dispute state of a transaction
+    fn set_dispute_state(
+        &mut self,
+        tx_id: &str,
+        state: DisputeState,
+    ) -> Result<(), KoalaError> {
+        if let Some(transaction) = self.txs.get_mut(tx_id) {
+            transaction.state = state;
+        }
+        Ok(())
+    }
+
+    /// Returns a client record, given a client id
+    fn get_client_record(
+        &self,
+        client_id: u16,
+    ) -> Result<ClientRecord, KoalaError> {
+        Ok(self.records.get(client_id))
+    }
+
+    /// Whether the client has a record
+    fn client_exists(&self, client_id: u16) -> Result<bool, KoalaError> {
+        Ok(self.records.contains(client_id))
+    }
+
+    /// Updates the client record, with the new record
+    fn update_record(&mut self, cr: &ClientRecord) -> Result<(), KoalaError> {
+        self.records.update(cr);
+        Ok(())
+    }
+
+    /// Writes the account table, sorted by client id
+    fn finish(&mut self) -> Result<(), KoalaError> {
+        self.records.write_table(&mut self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TransactionKind;
+    use rand::{thread_rng, Rng};
+
+    const TMP_OUT_FILE: &str = "data/tmp/tmp_mem_out_test.csv";
+
+    #[test]
+    fn test_deposit_tx() {
+        let mut processor = prep_test();
+        let tx_type = TransactionKind::Deposit;
+        let client_id = 1;
+        let tx_id = String::from("1");
+        let amount: Money = "10.0".parse().unwrap();
+
+        let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
+        processor.deposit_tx(tx).unwrap();
+
+        // Verify the transaction result
+        let cr = processor.get_client_record(client_id).unwrap();
+
+        assert_eq!(cr.available, amount);
+        assert_eq!(cr.total, amount);
+        assert_eq!(cr.held, Money::ZERO);
+    }
+
+    #[test]
+    fn test_withdrawal_tx() {
+        let mut processor = prep_test();
+
+        // We must do a deposit first
+        let tx_type = TransactionKind::Deposit;
+        let client_id = 1;
+        let tx_id = String::from("1");
+        let amount: Money = "10.0".parse().unwrap();
+        let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
+        processor.deposit_tx(tx).unwrap();
+
+        // Withdrawal now
+        let tx_type = TransactionKind::Withdrawal;
+        let tx_id = String::from("2");
+        let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
+        processor.withdrawal_tx(tx).unwrap();
+
+        // Verify the result
+        let cr = processor.get_client_record(client_id).unwrap();
+        assert_eq!(cr.available, Money::ZERO);
+        assert_eq!(cr.total, Money::ZERO);
+        assert_eq!(cr.held, Money::ZERO);
+
+        // Nothing left to withdraw
+        let tx_type = TransactionKind::Withdrawal;
+        let tx_id = String::from("3");
+        let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
+        assert!(matches!(
+            processor.withdrawal_tx(tx),
+            Err(KoalaError::BalanceError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_dispute_resolve_chargeback_tx() {
+        let mut processor = prep_test();
+
+        // We must do a deposit first
+        let tx_type = TransactionKind::Deposit;
+        let client_id = 1;
+        let tx_id = String::from("1");
+        let amount: Money = "10.0".parse().unwrap();
+        let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
+        processor.deposit_tx(tx).unwrap();
+
+        // Dispute and resolve
+        let tx = Transaction::new(TransactionKind::Dispute, 1, "1".into(), None);
+        processor.dispute_tx(&tx).unwrap();
+        let cr = processor.get_client_record(client_id).unwrap();
+        assert_eq!(cr.available, Money::ZERO);
+        assert_eq!(cr.held, amount);
+
+        let tx = Transaction::new(TransactionKind::Resolve, 1, "1".into(), None);
+        processor.resolve_tx(&tx).unwrap();
+        let cr = processor.get_client_record(client_id).unwrap();
+        assert_eq!(cr.available, amount);
+        assert_eq!(cr.held, Money::ZERO);
+
+        // Dispute and chargeback another deposit
+        let tx = Transaction::new(TransactionKind::Deposit, 1, "2".into(), Some(amount));
+        processor.deposit_tx(tx).unwrap();
+        let tx = Transaction::new(TransactionKind::Dispute, 1, "2".into(), None);
+        processor.dispute_tx(&tx).unwrap();
+        let tx =
+            Transaction::new(TransactionKind::Chargeback, 1, "2".into(), None);
+        processor.chargeback_tx(&tx).unwrap();
+
+        // Only the resolved deposit is left
+        let cr = processor.get_client_record(client_id).unwrap();
+        assert_eq!(cr.available, amount);
+        assert_eq!(cr.total, amount);
+        assert_eq!(cr.held, Money::ZERO);
+        assert!(cr.locked);
+    }
+
+    #[test]
+    fn test_finish_writes_account_table() {
+        let rnum: u32 = thread_rng().gen();
+        let path = format!("{}_{}", TMP_OUT_FILE, rnum);
+        let writer = std::fs::File::create(&path).unwrap();
+        let mut processor = MemoryProcessor::new(writer).unwrap();
+
+        let amount: Money = "1.25".parse().unwrap();
+        for (client_id, tx_id) in [(3, "1"), (1, "2"), (2, "3")] {
+            let tx_type = TransactionKind::Deposit;
+            let tx = Transaction::new(
+                tx_type,
+                client_id,
+                String::from(tx_id),
+                Some(amount),
+            );
+            processor.deposit_tx(tx).unwrap();
+        }
+        processor.finish().unwrap();
+
+        let output = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n\
+             1,1.2500,0.0000,1.2500,false\n\
+             2,1.2500,0.0000,1.2500,false\n\
+             3,1.2500,0.0000,1.2500,false\n"
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn prep_test() -> MemoryProcessor {
+        MemoryProcessor::new(std::io::sink()).unwrap()
+    }
+}
@@ -0,0 +1,26 @@
+-- Tables as created before the schema was versioned, so databases
+-- of earlier runs are taken over, once balances kept as REAL are
+-- converted by legacy_real_balances.sql
+CREATE TABLE IF NOT EXISTS CLIENT_RECORDS (
+    id INTEGER PRIMARY KEY,
+    available INTEGER,
+    held INTEGER,
+    total INTEGER,
+    locked BOOL
+);
+
+CREATE TABLE IF NOT EXISTS TRANSACTIONS (
+    tx TEXT PRIMARY KEY,
+    client INTEGER,
+    type TEXT,
+    amount INTEGER,
+    reason TEXT,
+    state TEXT
+);
+
+-- A single row, replaced as processing goes on
+CREATE TABLE IF NOT EXISTS CHECKPOINT (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    input_hash TEXT,
+    line INTEGER
+);
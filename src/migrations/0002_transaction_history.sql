@@ -0,0 +1,22 @@
+-- Transactions are numbered in the order they were applied and
+-- stamped, the unique constraint indexes them by id. Rows already
+-- kept are numbered in insertion order and stamped with the time
+-- of the upgrade.
+CREATE TABLE TRANSACTIONS_V2 (
+    seq INTEGER PRIMARY KEY,
+    tx TEXT NOT NULL UNIQUE,
+    client INTEGER NOT NULL,
+    type TEXT NOT NULL,
+    amount INTEGER,
+    reason TEXT,
+    state TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+INSERT INTO TRANSACTIONS_V2 ( tx, client, type, amount, reason, state )
+    SELECT tx, client, type, amount, reason, state FROM TRANSACTIONS ORDER BY rowid;
+
+DROP TABLE TRANSACTIONS;
+ALTER TABLE TRANSACTIONS_V2 RENAME TO TRANSACTIONS;
+
+CREATE INDEX TRANSACTIONS_CLIENT ON TRANSACTIONS ( client, seq );
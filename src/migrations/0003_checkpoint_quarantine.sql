@@ -0,0 +1,3 @@
+-- Clients quarantined up to the checkpoint, as comma separated ids,
+-- so a resumed run keeps holding back their transactions
+ALTER TABLE CHECKPOINT ADD COLUMN quarantined TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,20 @@
+-- The first releases kept balances as REAL, they are rebuilt as
+-- integer minor units at 4 decimals before the first migration
+CREATE TABLE CLIENT_RECORDS_V1 (
+    id INTEGER PRIMARY KEY,
+    available INTEGER,
+    held INTEGER,
+    total INTEGER,
+    locked BOOL
+);
+
+INSERT INTO CLIENT_RECORDS_V1 ( id, available, held, total, locked )
+    SELECT id,
+        CAST(ROUND(available * 10000) AS INTEGER),
+        CAST(ROUND(held * 10000) AS INTEGER),
+        CAST(ROUND(total * 10000) AS INTEGER),
+        locked
+    FROM CLIENT_RECORDS;
+
+DROP TABLE CLIENT_RECORDS;
+ALTER TABLE CLIENT_RECORDS_V1 RENAME TO CLIENT_RECORDS;
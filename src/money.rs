@@ -0,0 +1,215 @@
+use crate::errors::KoalaError;
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Number of decimal places kept for every amount
+pub const DECIMALS: u32 = 4;
+
+/// Minor units in one whole unit of currency
+const SCALE: i64 = 10_i64.pow(DECIMALS);
+
+/// Fixed-point decimal amount with four decimal places.
+///
+/// The value is kept as a signed count of minor units (1/10000th of
+/// a unit), so arithmetic is exact and overflow is checked. It is read
+/// and written as a decimal string in CSV and stored as an integer
+/// in SQLite.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    /// Returns an amount from a count of minor units
+    pub const fn from_minor(minor: i64) -> Self {
+        Money(minor)
+    }
+
+    /// Returns the amount as a count of minor units
+    pub const fn minor(self) -> i64 {
+        self.0
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// Adds two amounts, failing on overflow
+    pub fn checked_add(self, other: Money) -> Result<Money, KoalaError> {
+        self.0
+            .checked_add(other.0)
+            .map(Money)
+            .ok_or(KoalaError::OverflowError)
+    }
+
+    /// Subtracts two amounts, failing on overflow
+    pub fn checked_sub(self, other: Money) -> Result<Money, KoalaError> {
+        self.0
+            .checked_sub(other.0)
+            .map(Money)
+            .ok_or(KoalaError::OverflowError)
+    }
+}
+
+impl FromStr for Money {
+    type Err = KoalaError;
+
+    /// Parses a decimal string such as `12`, `-3.5` or `0.0001`.
+    /// More than four decimal places is rejected rather than rounded.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (whole, fraction) = match digits.split_once('.') {
+            Some((whole, fraction)) => (whole, fraction),
+            None => (digits, ""),
+        };
+
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if (whole.is_empty() && fraction.is_empty())
+            || !is_digits(whole)
+            || !is_digits(fraction)
+            || fraction.len() > DECIMALS as usize
+        {
+            return Err(KoalaError::InputError(s.to_string()));
+        }
+
+        let whole: i64 = if whole.is_empty() {
+            0
+        } else {
+            whole.parse().map_err(|_| KoalaError::OverflowError)?
+        };
+        let fraction: i64 = if fraction.is_empty() {
+            0
+        } else {
+            let padding = DECIMALS - fraction.len() as u32;
+            fraction
+                .parse::<i64>()
+                .map_err(|_| KoalaError::InputError(s.to_string()))?
+                * 10_i64.pow(padding)
+        };
+
+        let minor = whole
+            .checked_mul(SCALE)
+            .and_then(|m| m.checked_add(fraction))
+            .ok_or(KoalaError::OverflowError)?;
+
+        Ok(Money(if negative { -minor } else { minor }))
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let scale = SCALE as u64;
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            abs / scale,
+            abs % scale,
+            width = DECIMALS as usize
+        )
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MoneyVisitor;
+
+        impl<'de> Visitor<'de> for MoneyVisitor {
+            type Value = Money;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a decimal amount with at most {} decimal places", DECIMALS)
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Money, E> {
+                v.parse()
+                    .map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+            }
+        }
+
+        deserializer.deserialize_str(MoneyVisitor)
+    }
+}
+
+impl ToSql for Money {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.0))
+    }
+}
+
+impl FromSql for Money {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        i64::column_result(value).map(Money)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        let cases = [
+            ("10", "10.0000"),
+            ("10.5", "10.5000"),
+            ("0.0001", "0.0001"),
+            (".25", "0.2500"),
+            ("-3.1415", "-3.1415"),
+            ("301308.61", "301308.6100"),
+        ];
+
+        for (input, expected) in cases {
+            let money: Money = input.parse().unwrap();
+            assert_eq!(money.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid() {
+        for input in ["", ".", "abc", "1.23456", "1,5", "--1", "1e5"] {
+            assert!(input.parse::<Money>().is_err(), "{:?} parsed", input);
+        }
+    }
+
+    #[test]
+    fn test_exact_arithmetic() {
+        // 0.1 + 0.2 drifts with floats, but not here
+        let a: Money = "0.1".parse().unwrap();
+        let b: Money = "0.2".parse().unwrap();
+        assert_eq!(a.checked_add(b).unwrap(), "0.3".parse().unwrap());
+
+        let mut total = Money::ZERO;
+        for _ in 0..1_000_000 {
+            total = total.checked_add("0.01".parse().unwrap()).unwrap();
+        }
+        assert_eq!(total.to_string(), "10000.0000");
+    }
+
+    #[test]
+    fn test_checked_overflow() {
+        let max = Money::from_minor(i64::MAX);
+        assert!(max.checked_add(Money::from_minor(1)).is_err());
+
+        let min = Money::from_minor(i64::MIN);
+        assert!(min.checked_sub(Money::from_minor(1)).is_err());
+    }
+}
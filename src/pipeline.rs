@@ -0,0 +1,318 @@
+use crate::engine::{
+    ClientRecord, DisputeState, Engine, EngineConfig, Transaction,
+};
+use crate::errors::KoalaError;
+use crate::mem::{MemoryProcessor, RecordStore};
+use crate::rejection::Rejection;
+use crate::summary::RunSummary;
+use crate::{ErrorPolicy, ProcessOptions, Run};
+use csv::{ReaderBuilder, StringRecord, Trim};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use tracing::{debug, info, trace};
+
+/// Rows queued for a worker before the reader waits for it
+const QUEUE_SIZE: usize = 1024;
+
+/// Transactions of every shard, by tx id
+type SharedTxs = Arc<Mutex<HashMap<String, Transaction>>>;
+
+/// A row handed to the worker of its client
+struct Job {
+    line: u64,
+    row: StringRecord,
+    record: Transaction,
+}
+
+/// What became of a row
+enum Outcome {
+    /// Applied or refused, the worker keeps count
+    Done,
+    /// The row stopped the run, with a fatal error or
+    /// a refusal under the abort policy
+    Stopped(KoalaError),
+}
+
+/// Engine of a worker, it owns the client records of its shard.
+/// Transactions live in one store shared by every shard, since a
+/// tx id may be used or referenced by clients of other shards.
+struct ShardProcessor {
+    txs: SharedTxs,
+    records: RecordStore,
+    config: EngineConfig,
+}
+
+impl Engine for ShardProcessor {
+    fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// Keeps the transaction for later disputes
+    fn store_transaction(&mut self, tx: Transaction) -> Result<(), KoalaError> {
+        let mut txs = self.txs.lock().unwrap();
+        txs.entry(tx.tx.clone()).or_insert(tx);
+        Ok(())
+    }
+
+    /// Returns a transaction, given a transaction id
+    fn find_original_transaction(
+        &self,
+        tx_id: &str,
+    ) -> Result<Option<Transaction>, KoalaError> {
+        Ok(self.txs.lock().unwrap().get(tx_id).cloned())
+    }
+
+    /// Updates the dispute state of a transaction
+    fn set_dispute_state(
+        &mut self,
+        tx_id: &str,
+        state: DisputeState,
+    ) -> Result<(), KoalaError> {
+        if let Some(transaction) = self.txs.lock().unwrap().get_mut(tx_id) {
+            transaction.state = state;
+        }
+        Ok(())
+    }
+
+    /// Returns a client record, given a client id
+    fn get_client_record(
+        &self,
+        client_id: u16,
+    ) -> Result<ClientRecord, KoalaError> {
+        Ok(self.records.get(client_id))
+    }
+
+    /// Whether the client has a record in this shard
+    fn client_exists(&self, client_id: u16) -> Result<bool, KoalaError> {
+        Ok(self.records.contains(client_id))
+    }
+
+    /// Updates the client record, with the new record
+    fn update_record(&mut self, cr: &ClientRecord) -> Result<(), KoalaError> {
+        self.records.update(cr);
+        Ok(())
+    }
+}
+
+/// Processes the input like `process_txs` with a memory processor,
+/// on `threads` workers. Rows are sharded by client, so the rows of
+/// a client are applied in input order by the same worker. A row
+/// using a tx id another client used before waits for every earlier
+/// row, which keeps the account table and the refused transactions
+/// the same as those of a sequential run.
+pub fn process_txs_sharded(
+    input_file: String,
+    writer: impl Write + 'static,
+    config: EngineConfig,
+    options: &ProcessOptions,
+    threads: usize,
+) -> Result<RunSummary, KoalaError> {
+    let started = Instant::now();
+    let threads = threads.max(1);
+    let txs: SharedTxs = Arc::new(Mutex::new(HashMap::new()));
+    let mut rdr =
+        ReaderBuilder::new().trim(Trim::All).from_path(input_file)?;
+    let headers = rdr.headers()?.clone();
+
+    let (mut stops, mut summary, shards) = thread::scope(|scope| {
+        let (outcome_tx, outcome_rx) = mpsc::channel();
+        let mut workers = Vec::new();
+        let mut handles = Vec::new();
+        for _ in 0..threads {
+            let (job_tx, job_rx) = mpsc::sync_channel(QUEUE_SIZE);
+            let shard = ShardProcessor {
+                txs: txs.clone(),
+                records: RecordStore::default(),
+                config: config.clone(),
+            };
+            let outcome_tx = outcome_tx.clone();
+            handles.push(scope.spawn(move || {
+                run_shard(shard, job_rx, outcome_tx, options)
+            }));
+            workers.push(job_tx);
+        }
+        drop(outcome_tx);
+
+        let mut dispatcher = Dispatcher {
+            workers,
+            outcome_rx,
+            pending: 0,
+            stops: Vec::new(),
+            summary: RunSummary::default(),
+            owners: HashMap::new(),
+        };
+        let read = dispatcher.dispatch_rows(&mut rdr, &headers, options);
+
+        // Hang up on the workers, so they finish once their queue is empty
+        dispatcher.workers.clear();
+        dispatcher.wait_for_pending();
+        let shards: Vec<_> = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+        read.map(|_| (dispatcher.stops, dispatcher.summary, shards))
+    })?;
+
+    // The first row that stopped the run decides its error, as
+    // later rows would not have been read by a sequential run
+    stops.sort_by_key(|(line, _)| *line);
+    if let Some((_, err)) = stops.into_iter().next() {
+        return Err(err);
+    }
+
+    let mut processor = MemoryProcessor::new(writer)?;
+    for shard in shards {
+        let (records, shard_summary) = shard?;
+        for record in records {
+            processor.update_record(&record)?;
+        }
+        summary.merge(shard_summary);
+    }
+    processor.finish()?;
+    summary.rejections.sort_by_key(|rejection| rejection.line);
+    summary.timed(started.elapsed());
+    debug!(threads, refused = summary.rejections.len(), "finished");
+
+    Ok(summary)
+}
+
+/// Reads the input and hands each row to the worker of its client
+struct Dispatcher {
+    workers: Vec<SyncSender<Job>>,
+    outcome_rx: Receiver<(u64, Outcome)>,
+    /// Rows handed out, whose outcome hasn't come back yet
+    pending: usize,
+    /// Rows which stopped the run, by line
+    stops: Vec<(u64, KoalaError)>,
+    /// Counts of the rows which could not be parsed
+    summary: RunSummary,
+    /// Client of each tx id, None once several clients used it
+    owners: HashMap<String, Option<u16>>,
+}
+
+impl Dispatcher {
+    fn dispatch_rows(
+        &mut self,
+        rdr: &mut csv::Reader<std::fs::File>,
+        headers: &StringRecord,
+        options: &ProcessOptions,
+    ) -> Result<(), KoalaError> {
+        for result in rdr.records() {
+            let row = result?;
+            let line = row.position().map_or(0, |pos| pos.line());
+
+            let record: Transaction = match row.deserialize(Some(headers)) {
+                Ok(record) => record,
+                Err(err) => {
+                    let reason = KoalaError::from(err);
+                    info!(line, code = reason.code(), "refused: {}", reason);
+                    let rejection = Rejection {
+                        source: None,
+                        line,
+                        row,
+                        client: None,
+                        tx: None,
+                        reason,
+                    };
+                    if options.error_policy == ErrorPolicy::Abort {
+                        let err = KoalaError::AbortedError(Box::new(rejection));
+                        self.stops.push((line, err));
+                        break;
+                    }
+                    self.summary.malformed(rejection);
+                    continue;
+                }
+            };
+
+            if self.is_shared(&record) {
+                trace!(line, tx = record.tx.as_str(), "waiting for other shards");
+                self.wait_for_pending();
+            }
+            let worker = record.client as usize % self.workers.len();
+            self.workers[worker]
+                .send(Job { line, row, record })
+                .expect("workers outlive the dispatcher");
+            self.pending += 1;
+
+            while let Ok((line, outcome)) = self.outcome_rx.try_recv() {
+                self.pending -= 1;
+                self.record_outcome(line, outcome);
+            }
+            if !self.stops.is_empty() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the row's tx id was used by another client,
+    /// remembering the client for the rows after it
+    fn is_shared(&mut self, record: &Transaction) -> bool {
+        match self.owners.get_mut(&record.tx) {
+            None => {
+                self.owners.insert(record.tx.clone(), Some(record.client));
+                false
+            }
+            Some(Some(owner)) if *owner == record.client => false,
+            Some(owner) => {
+                *owner = None;
+                true
+            }
+        }
+    }
+
+    /// Collects every outstanding outcome
+    fn wait_for_pending(&mut self) {
+        while self.pending > 0 {
+            let (line, outcome) = self
+                .outcome_rx
+                .recv()
+                .expect("workers report every row");
+            self.pending -= 1;
+            self.record_outcome(line, outcome);
+        }
+    }
+
+    fn record_outcome(&mut self, line: u64, outcome: Outcome) {
+        if let Outcome::Stopped(err) = outcome {
+            self.stops.push((line, err));
+        }
+    }
+}
+
+/// Applies the rows of one shard, returning its client
+/// records and what its rows did
+fn run_shard(
+    mut shard: ShardProcessor,
+    jobs: Receiver<Job>,
+    outcomes: Sender<(u64, Outcome)>,
+    options: &ProcessOptions,
+) -> Result<(Vec<ClientRecord>, RunSummary), KoalaError> {
+    // The shard is lent to a run of its own, which applies
+    // its rows like those of a sequential run
+    let mut run = Run::new(&mut shard, options, None);
+    let mut stopped = false;
+
+    for Job { line, row, record } in jobs {
+        // Rows after a stop are only acknowledged
+        let outcome = if stopped {
+            Outcome::Done
+        } else {
+            match run.handle_row(None, false, line, row, Ok(record)) {
+                Ok(()) => Outcome::Done,
+                Err(err) => {
+                    stopped = true;
+                    Outcome::Stopped(err)
+                }
+            }
+        };
+        outcomes.send((line, outcome)).ok();
+    }
+
+    let summary = run.finish()?;
+    Ok((shard.records.into_sorted(), summary))
+}
@@ -0,0 +1,193 @@
+use crate::errors::KoalaError;
+use csv::StringRecord;
+use serde::Serialize;
+use std::io::Write;
+
+/// A transaction refused by the engine, or a row
+/// which could not be read as a transaction
+#[derive(Debug)]
+pub struct Rejection {
+    /// Name of the source the row came from, when
+    /// there are several
+    pub source: Option<String>,
+    /// Line of the row in its input
+    pub line: u64,
+    /// The row as it was read
+    pub row: StringRecord,
+    /// Client of the row, when it could be read
+    pub client: Option<u16>,
+    /// Transaction id of the row, when it could be read
+    pub tx: Option<String>,
+    pub reason: KoalaError,
+}
+
+/// File format of the rejected transaction report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Csv,
+    JsonLines,
+}
+
+/// One line of the rejected transaction report
+#[derive(Serialize)]
+struct ReportEntry<'a> {
+    source: Option<&'a str>,
+    line: u64,
+    client: Option<u16>,
+    tx: Option<&'a str>,
+    code: &'static str,
+    reason: String,
+    row: String,
+}
+
+impl<'a> ReportEntry<'a> {
+    fn new(rejection: &'a Rejection) -> Result<Self, KoalaError> {
+        Ok(ReportEntry {
+            source: rejection.source.as_deref(),
+            line: rejection.line,
+            client: rejection.client,
+            tx: rejection.tx.as_deref(),
+            code: rejection.reason.code(),
+            reason: rejection.reason.to_string(),
+            row: row_text(&rejection.row)?,
+        })
+    }
+}
+
+/// Writes the row out as a csv line, quoting the fields
+/// that need it so the line reads back as the same fields
+fn row_text(row: &StringRecord) -> Result<String, KoalaError> {
+    let mut text = Vec::new();
+    let mut wtr = csv::WriterBuilder::new()
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(&mut text);
+    wtr.write_record(row)?;
+    wtr.flush()?;
+    drop(wtr);
+    text.pop();
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+/// Writes every rejection to the report, one row or json
+/// object per rejection with the original row, its line,
+/// client, tx id and reason code
+pub fn write_report(
+    writer: impl Write,
+    format: ReportFormat,
+    rejections: &[Rejection],
+) -> Result<(), KoalaError> {
+    match format {
+        ReportFormat::Csv => {
+            let mut wtr = csv::Writer::from_writer(writer);
+            for rejection in rejections {
+                wtr.serialize(ReportEntry::new(rejection)?)?;
+            }
+            wtr.flush()?;
+        }
+        ReportFormat::JsonLines => {
+            let mut writer = std::io::BufWriter::new(writer);
+            for rejection in rejections {
+                serde_json::to_writer(&mut writer, &ReportEntry::new(rejection)?)
+                    .map_err(std::io::Error::from)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejections() -> Vec<Rejection> {
+        vec![
+            Rejection {
+                source: None,
+                line: 3,
+                row: StringRecord::from(vec!["withdrawal", "1", "2", "20.0"]),
+                client: Some(1),
+                tx: Some("2".to_string()),
+                reason: KoalaError::BalanceError {
+                    client: 1,
+                    tx: "2".to_string(),
+                    available: "10".parse().unwrap(),
+                    requested: "20".parse().unwrap(),
+                },
+            },
+            Rejection {
+                source: Some("tcp://10.0.0.7:9000".to_string()),
+                line: 4,
+                row: StringRecord::from(vec!["deposit", "one", "3", "1.0"]),
+                client: None,
+                tx: None,
+                reason: KoalaError::InputError("one".to_string()),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_csv_report() {
+        let mut output = Vec::new();
+        write_report(&mut output, ReportFormat::Csv, &rejections()).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "source,line,client,tx,code,reason,row\n\
+             ,3,1,2,insufficient_funds,\"client 1 has 10.0000 available, tx 2 needs 20.0000\",\"withdrawal,1,2,20.0\"\n\
+             tcp://10.0.0.7:9000,4,,,invalid_input,\"invalid input \"\"one\"\"\",\"deposit,one,3,1.0\"\n"
+        );
+    }
+
+    #[test]
+    fn test_row_keeps_its_fields() {
+        let rejection = Rejection {
+            source: None,
+            line: 7,
+            row: StringRecord::from(vec![
+                "adjustment",
+                "1",
+                "5",
+                "2.5",
+                "goodwill, \"per\" ticket 7",
+            ]),
+            client: Some(1),
+            tx: Some("5".to_string()),
+            reason: KoalaError::UnauthorizedError {
+                client: 1,
+                tx: "5".to_string(),
+                kind: crate::engine::TransactionKind::Adjustment,
+            },
+        };
+        let entry = ReportEntry::new(&rejection).unwrap();
+        assert_eq!(entry.row, r#"adjustment,1,5,2.5,"goodwill, ""per"" ticket 7""#);
+
+        // It reads back as the original fields
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(entry.row.as_bytes());
+        let read = rdr.records().next().unwrap().unwrap();
+        assert_eq!(read, rejection.row);
+    }
+
+    #[test]
+    fn test_json_lines_report() {
+        let mut output = Vec::new();
+        write_report(&mut output, ReportFormat::JsonLines, &rejections())
+            .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            r#"{"source":null,"line":3,"client":1,"tx":"2","code":"insufficient_funds","reason":"client 1 has 10.0000 available, tx 2 needs 20.0000","row":"withdrawal,1,2,20.0"}"#
+        );
+        assert_eq!(
+            lines[1],
+            r#"{"source":"tcp://10.0.0.7:9000","line":4,"client":null,"tx":null,"code":"invalid_input","reason":"invalid input \"one\"","row":"deposit,one,3,1.0"}"#
+        );
+    }
+}
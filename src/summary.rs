@@ -0,0 +1,285 @@
+use crate::engine::{Engine, Transaction, TransactionKind};
+use crate::errors::KoalaError;
+use crate::money::Money;
+use crate::rejection::Rejection;
+use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::time::Duration;
+
+/// What a processing run did
+#[derive(Debug, Default, Serialize)]
+pub struct RunSummary {
+    /// Rows read, including those which could not be parsed
+    pub rows: u64,
+    /// Rows of each transaction kind
+    pub kinds: BTreeMap<&'static str, u64>,
+    pub accepted: u64,
+    /// Exact repeats ignored under the replay policy, which
+    /// are neither accepted nor refused
+    pub replayed: u64,
+    /// Refused rows, by reason code
+    pub refused: BTreeMap<&'static str, u64>,
+    /// Amount of the accepted deposits
+    pub deposited: Money,
+    /// Amount of the accepted withdrawals
+    pub withdrawn: Money,
+    /// Amount put on hold by accepted disputes
+    pub held: Money,
+    /// Amount of the accepted chargebacks
+    pub charged_back: Money,
+    /// Clients which had no account before the run
+    /// and had a transaction applied
+    pub clients_created: u64,
+    /// Clients which were locked during the run
+    pub clients_locked: u64,
+    pub elapsed_secs: f64,
+    pub rows_per_sec: f64,
+    /// The refused rows, in input order
+    #[serde(skip)]
+    pub rejections: Vec<Rejection>,
+}
+
+impl RunSummary {
+    /// Number of refused rows
+    pub fn refused_total(&self) -> u64 {
+        self.refused.values().sum()
+    }
+
+    /// Writes the summary as an aligned, human readable table
+    pub fn write_table(&self, mut writer: impl Write) -> std::io::Result<()> {
+        writeln!(writer, "{:<24}{:>20}", "rows", self.rows)?;
+        for (kind, count) in &self.kinds {
+            writeln!(writer, "  {:<22}{:>20}", kind, count)?;
+        }
+        writeln!(writer, "{:<24}{:>20}", "accepted", self.accepted)?;
+        writeln!(writer, "{:<24}{:>20}", "replayed", self.replayed)?;
+        writeln!(writer, "{:<24}{:>20}", "refused", self.refused_total())?;
+        for (code, count) in &self.refused {
+            writeln!(writer, "  {:<22}{:>20}", code, count)?;
+        }
+        // Money ignores the width, so its text is aligned
+        writeln!(writer, "{:<24}{:>20}", "deposited", self.deposited.to_string())?;
+        writeln!(writer, "{:<24}{:>20}", "withdrawn", self.withdrawn.to_string())?;
+        writeln!(writer, "{:<24}{:>20}", "held", self.held.to_string())?;
+        writeln!(writer, "{:<24}{:>20}", "charged back", self.charged_back.to_string())?;
+        writeln!(writer, "{:<24}{:>20}", "clients created", self.clients_created)?;
+        writeln!(writer, "{:<24}{:>20}", "clients locked", self.clients_locked)?;
+        writeln!(writer, "{:<24}{:>19.3}s", "elapsed", self.elapsed_secs)?;
+        writeln!(writer, "{:<24}{:>13.0} rows/s", "throughput", self.rows_per_sec)?;
+        Ok(())
+    }
+
+    /// Writes the summary as a json object on one line
+    pub fn write_json(&self, mut writer: impl Write) -> std::io::Result<()> {
+        serde_json::to_writer(&mut writer, self)?;
+        writeln!(writer)
+    }
+
+    /// Counts a row which could not be parsed
+    pub(crate) fn malformed(&mut self, rejection: Rejection) {
+        self.rows += 1;
+        self.refuse(rejection);
+    }
+
+    fn refuse(&mut self, rejection: Rejection) {
+        *self.refused.entry(rejection.reason.code()).or_default() += 1;
+        self.rejections.push(rejection);
+    }
+
+    /// Adds the counts of another part of the same run
+    pub(crate) fn merge(&mut self, other: RunSummary) {
+        self.rows += other.rows;
+        for (kind, count) in other.kinds {
+            *self.kinds.entry(kind).or_default() += count;
+        }
+        self.accepted += other.accepted;
+        self.replayed += other.replayed;
+        for (code, count) in other.refused {
+            *self.refused.entry(code).or_default() += count;
+        }
+        add(&mut self.deposited, other.deposited);
+        add(&mut self.withdrawn, other.withdrawn);
+        add(&mut self.held, other.held);
+        add(&mut self.charged_back, other.charged_back);
+        self.clients_created += other.clients_created;
+        self.clients_locked += other.clients_locked;
+        self.rejections.extend(other.rejections);
+    }
+
+    /// Sets the wall-clock time of the run
+    pub(crate) fn timed(&mut self, elapsed: Duration) {
+        self.elapsed_secs = elapsed.as_secs_f64();
+        self.rows_per_sec = match self.elapsed_secs {
+            secs if secs > 0.0 => self.rows as f64 / secs,
+            _ => 0.0,
+        };
+    }
+}
+
+/// Adds to a total, which stops at the largest amount
+/// rather than fail the run
+fn add(total: &mut Money, amount: Money) {
+    *total = total
+        .checked_add(amount)
+        .unwrap_or(Money::from_minor(i64::MAX));
+}
+
+/// How a client was before its first row of the run
+struct SeenClient {
+    existed: bool,
+    locked: bool,
+    applied: bool,
+}
+
+/// The row being applied, as far as the summary cares
+pub(crate) struct Counted {
+    kind: TransactionKind,
+    client: u16,
+    tx: String,
+    amount: Option<Money>,
+}
+
+/// Keeps count of what the rows of a run did
+#[derive(Default)]
+pub(crate) struct Tally {
+    summary: RunSummary,
+    clients: HashMap<u16, SeenClient>,
+}
+
+impl Tally {
+    /// Counts a row which could not be parsed
+    pub(crate) fn malformed(&mut self, rejection: Rejection) {
+        self.summary.malformed(rejection);
+    }
+
+    /// Counts a parsed row before it is applied, noting how its
+    /// client was if it is the client's first row
+    pub(crate) fn read(
+        &mut self,
+        processor: &impl Engine,
+        record: &Transaction,
+    ) -> Result<Counted, KoalaError> {
+        self.summary.rows += 1;
+        *self.summary.kinds.entry(record.tx_type.as_str()).or_default() += 1;
+        if let Entry::Vacant(entry) = self.clients.entry(record.client) {
+            let cr = processor.get_client_record(record.client)?;
+            entry.insert(SeenClient {
+                existed: processor.client_exists(record.client)?,
+                locked: cr.locked,
+                applied: false,
+            });
+        }
+
+        Ok(Counted {
+            kind: record.tx_type,
+            client: record.client,
+            tx: record.tx.clone(),
+            amount: record.amount,
+        })
+    }
+
+    /// Counts an applied row, disputes and chargebacks
+    /// count the amount of the transaction they refer to
+    pub(crate) fn applied(
+        &mut self,
+        processor: &impl Engine,
+        counted: Counted,
+    ) -> Result<(), KoalaError> {
+        self.summary.accepted += 1;
+        if let Some(client) = self.clients.get_mut(&counted.client) {
+            client.applied = true;
+        }
+
+        let referenced = || -> Result<Money, KoalaError> {
+            Ok(processor
+                .find_original_transaction(&counted.tx)?
+                .and_then(|tx| tx.amount)
+                .unwrap_or_default())
+        };
+        let amount = counted.amount.unwrap_or_default();
+        match counted.kind {
+            TransactionKind::Deposit => add(&mut self.summary.deposited, amount),
+            TransactionKind::Withdrawal => {
+                add(&mut self.summary.withdrawn, amount)
+            }
+            TransactionKind::Dispute => add(&mut self.summary.held, referenced()?),
+            TransactionKind::Chargeback => {
+                add(&mut self.summary.charged_back, referenced()?)
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Counts a row ignored as a replay, which
+    /// moved no money
+    pub(crate) fn replayed(&mut self) {
+        self.summary.replayed += 1;
+    }
+
+    /// Counts a refused row
+    pub(crate) fn refused(&mut self, rejection: Rejection) {
+        self.summary.refuse(rejection);
+    }
+
+    /// Returns the summary, looking up which clients were
+    /// locked by the run. Call it before the processor finishes.
+    pub(crate) fn finish(
+        mut self,
+        processor: &impl Engine,
+    ) -> Result<RunSummary, KoalaError> {
+        for (id, client) in &self.clients {
+            if !client.existed && client.applied {
+                self.summary.clients_created += 1;
+            }
+            if !client.locked && processor.get_client_record(*id)?.locked {
+                self.summary.clients_locked += 1;
+            }
+        }
+        Ok(self.summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_and_json() {
+        let mut summary = RunSummary {
+            rows: 4,
+            accepted: 3,
+            deposited: "12.5".parse().unwrap(),
+            clients_created: 2,
+            ..RunSummary::default()
+        };
+        summary.kinds.insert("deposit", 3);
+        summary.kinds.insert("withdrawal", 1);
+        summary.refused.insert("insufficient_funds", 1);
+        summary.timed(Duration::from_millis(500));
+
+        let mut table = Vec::new();
+        summary.write_table(&mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], format!("{:<24}{:>20}", "rows", 4));
+        assert_eq!(lines[1], format!("  {:<22}{:>20}", "deposit", 3));
+        assert_eq!(lines[4], format!("{:<24}{:>20}", "replayed", 0));
+        assert_eq!(lines[5], format!("{:<24}{:>20}", "refused", 1));
+        assert_eq!(lines[7], format!("{:<24}{:>20}", "deposited", "12.5000"));
+        assert_eq!(lines[14], format!("{:<24}{:>13} rows/s", "throughput", 8));
+
+        let mut json = Vec::new();
+        summary.write_json(&mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"rows\":4,\"kinds\":{\"deposit\":3,\"withdrawal\":1},\"accepted\":3,\
+             \"replayed\":0,\"refused\":{\"insufficient_funds\":1},\"deposited\":\"12.5000\",\
+             \"withdrawn\":\"0.0000\",\"held\":\"0.0000\",\"charged_back\":\"0.0000\",\
+             \"clients_created\":2,\"clients_locked\":0,\"elapsed_secs\":0.5,\
+             \"rows_per_sec\":8.0}\n"
+        );
+    }
+}
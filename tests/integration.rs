@@ -1,11 +1,28 @@
 use koala_transaction_engine::cs::CSVProcessor;
 use koala_transaction_engine::db::{DBProcessor};
-use koala_transaction_engine::engine::{Engine, Transaction};
-use rand::{thread_rng, Rng};
+use koala_transaction_engine::engine::{
+    Checkpoint, ClientRecord, DisputeState, DuplicatePolicy, Effect, Engine,
+    EngineConfig, Transaction, TransactionKind, WithdrawalDisputePolicy,
+};
+use koala_transaction_engine::errors::KoalaError;
+use koala_transaction_engine::ingest::{process_sources, Source};
+use koala_transaction_engine::mem::MemoryProcessor;
+use koala_transaction_engine::money::Money;
+use koala_transaction_engine::pipeline::process_txs_sharded;
+use koala_transaction_engine::summary::RunSummary;
+use koala_transaction_engine::{
+    process_iter, process_reader, process_txs, validate_txs, ErrorPolicy,
+    ProcessOptions,
+};
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+use std::collections::BTreeMap;
 
 const TMP_CSV_FILE: &str = "data/tmp/tmp_test.csv";
 const TMP_CSV_OUT_FILE: &str = "data/tmp/tmp_out_test.csv";
 const TMP_DB: &str = "data/tmp/tmp_db.sqlite";
+const TMP_MEM_OUT_FILE: &str = "data/tmp/tmp_mem_out_test.csv";
+const TMP_INPUT_FILE: &str = "data/tmp/tmp_input_test.csv";
 
 #[test]
 fn test_csv_method() {
@@ -52,10 +69,1519 @@ fn test_db_method() {
     transact_and_verify(&mut processor);
 
     // Cleanup
-    if std::path::Path::new(db_file.as_str()).exists() {
-        println!("Cleaning {}", db_file);
-        std::fs::remove_file(db_file).unwrap();
+    remove_db(&db_file);
+}
+
+#[test]
+fn test_db_sessions_persist() {
+    let mut rng = thread_rng();
+    let rnum: u32 = rng.gen();
+    let db_file = format!("{}_{}", TMP_DB, rnum);
+    let input_file = format!("{}_{}", TMP_INPUT_FILE, rnum);
+
+    let days = [
+        "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+",
+        "type, client, tx, amount
+withdrawal, 1, 3, 4.0
+dispute, 2, 2,
+deposit, 1, 1, 10.0
+",
+    ];
+    let mut refused = Vec::new();
+    for day in days {
+        std::fs::write(input_file.as_str(), day).unwrap();
+        let processor = DBProcessor::open(db_file.clone()).unwrap();
+        let rejections =
+            process_txs(input_file.clone(), processor, &ProcessOptions::default())
+                .unwrap()
+                .rejections;
+        refused.extend(rejections.iter().map(|r| r.reason.code()));
+    }
+
+    // The second day builds on the first, and can't apply its txs again
+    assert_eq!(refused, vec!["duplicate_transaction"]);
+    let records = koala_transaction_engine::db::read_client_records(&db_file)
+        .unwrap();
+    let balances: Vec<(u16, Money, Money)> = records
+        .iter()
+        .map(|cr| (cr.id, cr.available, cr.held))
+        .collect();
+    assert_eq!(
+        balances,
+        vec![
+            (1, money("6.0"), Money::ZERO),
+            (2, Money::ZERO, money("5.0")),
+        ]
+    );
+
+    std::fs::remove_file(input_file).unwrap();
+    remove_db(&db_file);
+}
+
+#[test]
+fn test_resume_after_crash() {
+    let mut rng = thread_rng();
+    let rnum: u32 = rng.gen();
+    let db_file = format!("{}_{}", TMP_DB, rnum);
+    let input_file = format!("{}_{}", TMP_INPUT_FILE, rnum);
+
+    let input = "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+withdrawal, 1, 3, 4.0
+dispute, 2, 2,
+deposit, 1, 4, 1.0
+";
+    std::fs::write(input_file.as_str(), input).unwrap();
+    let resume = ProcessOptions {
+        resume: true,
+        batch_size: 2,
+        ..ProcessOptions::default()
+    };
+
+    // The first run dies halfway through the withdrawal, after the
+    // balance is updated, which is rolled back along with the batch
+    let processor = CrashingProcessor {
+        inner: DBProcessor::open(db_file.clone()).unwrap(),
+        crash_at: "3",
+    };
+    let result = process_txs(input_file.clone(), processor, &resume);
+    assert!(matches!(result, Err(KoalaError::LineError { line: 4, .. })));
+
+    // Resuming applies every row exactly once, a second resume
+    // has nothing left to do
+    for _ in 0..2 {
+        let processor = DBProcessor::open(db_file.clone()).unwrap();
+        let rejections = process_txs(input_file.clone(), processor, &resume)
+            .unwrap()
+            .rejections;
+        assert!(rejections.is_empty());
+    }
+    let records = koala_transaction_engine::db::read_client_records(&db_file)
+        .unwrap();
+    let balances: Vec<(u16, Money, Money)> = records
+        .iter()
+        .map(|cr| (cr.id, cr.available, cr.held))
+        .collect();
+    assert_eq!(
+        balances,
+        vec![
+            (1, money("7.0"), Money::ZERO),
+            (2, Money::ZERO, money("5.0")),
+        ]
+    );
+
+    // A different file can't pick up the checkpoint
+    std::fs::write(input_file.as_str(), "type, client, tx, amount\n").unwrap();
+    let processor = DBProcessor::open(db_file.clone()).unwrap();
+    let result = process_txs(input_file.clone(), processor, &resume);
+    assert!(matches!(result, Err(KoalaError::CheckpointError { .. })));
+
+    std::fs::remove_file(input_file).unwrap();
+    remove_db(&db_file);
+}
+
+#[test]
+fn test_resume_keeps_quarantined_clients() {
+    let mut rng = thread_rng();
+    let rnum: u32 = rng.gen();
+    let db_file = format!("{}_{}", TMP_DB, rnum);
+    let input_file = format!("{}_{}", TMP_INPUT_FILE, rnum);
+
+    let input = "type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 50.0
+deposit, 2, 3, 5.0
+deposit, 2, 4, 1.0
+deposit, 1, 5, 3.0
+";
+    std::fs::write(input_file.as_str(), input).unwrap();
+    let resume = ProcessOptions {
+        error_policy: ErrorPolicy::Quarantine,
+        resume: true,
+        batch_size: 2,
+    };
+
+    // Client 1 is quarantined in the batch committed before the crash
+    let processor = CrashingProcessor {
+        inner: DBProcessor::open(db_file.clone()).unwrap(),
+        crash_at: "4",
+    };
+    let result = process_txs(input_file.clone(), processor, &resume);
+    assert!(matches!(result, Err(KoalaError::LineError { line: 5, .. })));
+
+    // The resumed run still holds back its later deposit
+    let processor = DBProcessor::open(db_file.clone()).unwrap();
+    let rejections = process_txs(input_file.clone(), processor, &resume)
+        .unwrap()
+        .rejections;
+    let refused: Vec<(u64, &str)> =
+        rejections.iter().map(|r| (r.line, r.reason.code())).collect();
+    assert_eq!(refused, vec![(6, "client_quarantined")]);
+    let records = koala_transaction_engine::db::read_client_records(&db_file)
+        .unwrap();
+    let balances: Vec<(u16, Money)> =
+        records.iter().map(|cr| (cr.id, cr.available)).collect();
+    assert_eq!(balances, vec![(1, money("10.0")), (2, money("6.0"))]);
+
+    std::fs::remove_file(input_file).unwrap();
+    remove_db(&db_file);
+}
+
+/// Removes a test database along with its write-ahead log files
+fn remove_db(db_file: &str) {
+    for suffix in ["", "-wal", "-shm"] {
+        let file = format!("{}{}", db_file, suffix);
+        if std::path::Path::new(&file).exists() {
+            println!("Cleaning {}", file);
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+}
+
+/// Fails like a lost disk once it is asked to store the given tx
+struct CrashingProcessor {
+    inner: DBProcessor,
+    crash_at: &'static str,
+}
+
+impl Engine for CrashingProcessor {
+    fn config(&self) -> &EngineConfig {
+        self.inner.config()
+    }
+
+    fn store_transaction(&mut self, tx: Transaction) -> Result<(), KoalaError> {
+        if tx.tx == self.crash_at {
+            return Err(KoalaError::IO(std::io::Error::other("crash")));
+        }
+        self.inner.store_transaction(tx)
+    }
+
+    fn find_original_transaction(
+        &self,
+        tx_id: &str,
+    ) -> Result<Option<Transaction>, KoalaError> {
+        self.inner.find_original_transaction(tx_id)
+    }
+
+    fn set_dispute_state(
+        &mut self,
+        tx_id: &str,
+        state: DisputeState,
+    ) -> Result<(), KoalaError> {
+        self.inner.set_dispute_state(tx_id, state)
+    }
+
+    fn get_client_record(
+        &self,
+        client_id: u16,
+    ) -> Result<ClientRecord, KoalaError> {
+        self.inner.get_client_record(client_id)
+    }
+
+    fn client_exists(&self, client_id: u16) -> Result<bool, KoalaError> {
+        self.inner.client_exists(client_id)
+    }
+
+    fn update_record(&mut self, cr: &ClientRecord) -> Result<(), KoalaError> {
+        self.inner.update_record(cr)
+    }
+
+    fn begin_tx(&mut self) -> Result<(), KoalaError> {
+        self.inner.begin_tx()
+    }
+
+    fn commit_tx(&mut self) -> Result<(), KoalaError> {
+        self.inner.commit_tx()
+    }
+
+    fn rollback_tx(&mut self) -> Result<(), KoalaError> {
+        self.inner.rollback_tx()
+    }
+
+    fn commit_batch(&mut self) -> Result<(), KoalaError> {
+        self.inner.commit_batch()
+    }
+
+    fn keeps_checkpoints(&self) -> bool {
+        self.inner.keeps_checkpoints()
+    }
+
+    fn load_checkpoint(&self) -> Result<Option<Checkpoint>, KoalaError> {
+        self.inner.load_checkpoint()
+    }
+
+    fn save_checkpoint(
+        &mut self,
+        checkpoint: &Checkpoint,
+    ) -> Result<(), KoalaError> {
+        self.inner.save_checkpoint(checkpoint)
+    }
+}
+
+#[test]
+fn test_processors_agree_to_the_minor_unit() {
+    let mut rng = thread_rng();
+    let rnum: u32 = rng.gen();
+    let input_file = format!("{}_{}", TMP_INPUT_FILE, rnum);
+    let tmp_file = format!("{}_{}", TMP_CSV_FILE, rnum);
+    let tmp_out_file = format!("{}_{}", TMP_CSV_OUT_FILE, rnum);
+    let db_file = format!("{}_{}", TMP_DB, rnum);
+    let mem_out_file = format!("{}_{}", TMP_MEM_OUT_FILE, rnum);
+
+    let expected = generate_input(&input_file, 2000);
+
+    // Run the same input through both processors
+    std::fs::File::create(tmp_file.as_str()).unwrap();
+    std::fs::File::create(tmp_out_file.as_str()).unwrap();
+    let processor =
+        CSVProcessor::new(tmp_file.clone(), tmp_out_file.clone()).unwrap();
+    process_txs(input_file.clone(), processor, &ProcessOptions::default())
+        .unwrap();
+
+    std::fs::File::create(db_file.as_str()).unwrap();
+    let processor = DBProcessor::new(db_file.clone()).unwrap();
+    process_txs(input_file.clone(), processor, &ProcessOptions::default())
+        .unwrap();
+
+    let writer = std::fs::File::create(mem_out_file.as_str()).unwrap();
+    let processor = MemoryProcessor::new(writer).unwrap();
+    process_txs(input_file.clone(), processor, &ProcessOptions::default())
+        .unwrap();
+
+    // Verify both outputs against the expected balances
+    let read_records = |path: &str| -> BTreeMap<u16, ClientRecord> {
+        let mut rdr = csv::Reader::from_path(path).unwrap();
+        rdr.deserialize()
+            .map(|r| r.unwrap())
+            .map(|cr: ClientRecord| (cr.id, cr))
+            .collect()
+    };
+    let csv_records = read_records(tmp_out_file.as_str());
+    let mem_records = read_records(mem_out_file.as_str());
+
+    let conn = rusqlite::Connection::open(db_file.as_str()).unwrap();
+    let mut stmt = conn
+        .prepare("SELECT id, available, held, total FROM CLIENT_RECORDS")
+        .unwrap();
+    let db_records: BTreeMap<u16, (Money, Money, Money)> = stmt
+        .query_map([], |row| {
+            Ok((row.get(0)?, (row.get(1)?, row.get(2)?, row.get(3)?)))
+        })
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+
+    assert_eq!(csv_records.len(), expected.len());
+    assert_eq!(db_records.len(), expected.len());
+    assert_eq!(mem_records, csv_records);
+    for (client, (available, held)) in expected {
+        let total = available.checked_add(held).unwrap();
+
+        let cr = &csv_records[&client];
+        assert_eq!((cr.available, cr.held, cr.total), (available, held, total));
+        assert_eq!(db_records[&client], (available, held, total));
+    }
+
+    // Cleanup
+    drop(stmt);
+    drop(conn);
+    remove_db(&db_file);
+    for file in [input_file, tmp_file, tmp_out_file, mem_out_file] {
+        if std::path::Path::new(&file).exists() {
+            println!("Cleaning {}", file);
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_validate_reports_invalid_rows() {
+    let mut rng = thread_rng();
+    let rnum: u32 = rng.gen();
+    let input_file = format!("{}_{}", TMP_INPUT_FILE, rnum);
+
+    let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2,
+withdrawal, 1, 3, 0
+refund, 1, 4, 1.0
+dispute, 1, 1, 1.0
+resolve, 1, 1,
+chargeback, x, 1,
+Deposit, 1, 5, 2.0
+freeze, 1, 6,
+adjustment, 1, 7, 1.0
+";
+    std::fs::write(input_file.as_str(), input).unwrap();
+
+    let problems = validate_txs(input_file.clone()).unwrap();
+    let lines: Vec<u64> = problems.iter().map(|(line, _)| *line).collect();
+    assert_eq!(lines, vec![3, 4, 5, 6, 8, 10, 11]);
+    assert_eq!(problems[0].1, "deposit tx 2 of client 1 needs an amount");
+    assert_eq!(problems[3].1, "dispute tx 1 of client 1 can't have an amount");
+    assert_eq!(
+        problems[5].1,
+        "freeze tx 6 of client 1 is administrative and only accepted from an operator"
+    );
+
+    std::fs::remove_file(input_file).unwrap();
+}
+
+#[test]
+fn test_transaction_kinds() {
+    let mut rng = thread_rng();
+    let rnum: u32 = rng.gen();
+    let input_file = format!("{}_{}", TMP_INPUT_FILE, rnum);
+    let tmp_out_file = format!("{}_{}", TMP_CSV_OUT_FILE, rnum);
+
+    let input = "type, client, tx, amount
+Deposit, 1, 1, 10.0
+DEPOSIT, 1, 2, 1.0
+deposit, 1, 3,
+Dispute, 1, 1, 1.0
+deposit, 1, 4, ten
+transfer, 1, 5, 1.0
+dispute, 1, 1,
+";
+    std::fs::write(input_file.as_str(), input).unwrap();
+
+    let writer = std::fs::File::create(tmp_out_file.as_str()).unwrap();
+    let processor = MemoryProcessor::new(writer).unwrap();
+    let rejections =
+        process_txs(input_file.clone(), processor, &ProcessOptions::default())
+            .unwrap()
+            .rejections;
+
+    let refused: Vec<(u64, &str)> = rejections
+        .iter()
+        .map(|r| (r.line, r.reason.code()))
+        .collect();
+    assert_eq!(
+        refused,
+        vec![
+            (4, "amount_missing"),
+            (5, "amount_not_allowed"),
+            (6, "malformed_row"),
+            (7, "malformed_row"),
+        ]
+    );
+    assert_eq!(rejections[0].client, Some(1));
+    assert_eq!(rejections[0].tx.as_deref(), Some("3"));
+
+    let output = std::fs::read_to_string(tmp_out_file.as_str()).unwrap();
+    assert_eq!(
+        output,
+        "client,available,held,total,locked
+1,1.0000,10.0000,11.0000,false
+"
+    );
+
+    std::fs::remove_file(input_file).unwrap();
+    std::fs::remove_file(tmp_out_file).unwrap();
+}
+
+#[test]
+fn test_error_policies() {
+    let mut rng = thread_rng();
+    let rnum: u32 = rng.gen();
+    let input_file = format!("{}_{}", TMP_INPUT_FILE, rnum);
+    let tmp_out_file = format!("{}_{}", TMP_CSV_OUT_FILE, rnum);
+
+    let input = "type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 20.0
+deposit, 1, 3, 5.0
+deposit, 2, 4, 7.0
+deposit, two, 5, 1.0
+";
+    std::fs::write(input_file.as_str(), input).unwrap();
+
+    let run = |error_policy| {
+        let writer = std::fs::File::create(tmp_out_file.as_str()).unwrap();
+        let processor = MemoryProcessor::new(writer).unwrap();
+        let options = ProcessOptions {
+            error_policy,
+            ..ProcessOptions::default()
+        };
+        let result = process_txs(input_file.clone(), processor, &options);
+        let output = std::fs::read_to_string(tmp_out_file.as_str()).unwrap();
+        (result, output)
+    };
+
+    // Abort stops at the failed withdrawal, which comes back
+    // with the error
+    let (result, _) = run(ErrorPolicy::Abort);
+    match result {
+        Err(KoalaError::AbortedError(rejection)) => {
+            assert_eq!(rejection.line, 3);
+            assert_eq!(rejection.row.get(0), Some("withdrawal"));
+            assert!(matches!(rejection.reason, KoalaError::BalanceError { .. }));
+        }
+        _ => panic!("expected the run to abort at line 3"),
+    }
+
+    // Skip carries on with every other row
+    let (result, output) = run(ErrorPolicy::Skip);
+    let rejections = result.unwrap().rejections;
+    let lines: Vec<u64> = rejections.iter().map(|r| r.line).collect();
+    assert_eq!(lines, vec![3, 6]);
+    assert!(matches!(rejections[0].reason, KoalaError::BalanceError { .. }));
+    assert_eq!(rejections[0].client, Some(1));
+    assert_eq!(rejections[0].tx.as_deref(), Some("2"));
+    assert_eq!(rejections[1].client, None);
+    assert_eq!(
+        output,
+        "client,available,held,total,locked
+1,15.0000,0.0000,15.0000,false
+2,7.0000,0.0000,7.0000,false
+"
+    );
+
+    // Quarantine also holds back the later deposit of client 1
+    let (result, output) = run(ErrorPolicy::Quarantine);
+    let rejections = result.unwrap().rejections;
+    let lines: Vec<u64> = rejections.iter().map(|r| r.line).collect();
+    assert_eq!(lines, vec![3, 4, 6]);
+    assert!(matches!(rejections[1].reason, KoalaError::QuarantinedError { .. }));
+    assert_eq!(
+        output,
+        "client,available,held,total,locked
+1,10.0000,0.0000,10.0000,false
+2,7.0000,0.0000,7.0000,false
+"
+    );
+
+    std::fs::remove_file(input_file).unwrap();
+    std::fs::remove_file(tmp_out_file).unwrap();
+}
+
+#[test]
+fn test_in_memory_inputs() {
+    let rnum: u32 = thread_rng().gen();
+    let tmp_out_file = format!("{}_{}", TMP_MEM_OUT_FILE, rnum);
+    let expected = "client,available,held,total,locked
+1,7.5000,0.0000,7.5000,false
+2,3.0000,0.0000,3.0000,false
+";
+
+    // csv from any reader, without a file on disk
+    let input = "type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 2.5
+deposit, 2, 3, 3.0
+withdrawal, 2, 4, 5.0
+";
+    let writer = std::fs::File::create(tmp_out_file.as_str()).unwrap();
+    let processor = MemoryProcessor::new(writer).unwrap();
+    let rejections = process_reader(
+        std::io::Cursor::new(input),
+        processor,
+        &ProcessOptions::default(),
+    )
+    .unwrap()
+    .rejections;
+    let lines: Vec<u64> = rejections.iter().map(|r| r.line).collect();
+    assert_eq!(lines, vec![5]);
+    assert_eq!(std::fs::read_to_string(&tmp_out_file).unwrap(), expected);
+
+    // Transactions which are already parsed
+    let transactions = vec![
+        Transaction::new(TransactionKind::Deposit, 1, "1".into(), Some(money("10.0"))),
+        Transaction::new(TransactionKind::Withdrawal, 1, "2".into(), Some(money("2.5"))),
+        Transaction::new(TransactionKind::Deposit, 2, "3".into(), Some(money("3.0"))),
+        Transaction::new(TransactionKind::Withdrawal, 2, "4".into(), Some(money("5.0"))),
+    ];
+    let writer = std::fs::File::create(tmp_out_file.as_str()).unwrap();
+    let processor = MemoryProcessor::new(writer).unwrap();
+    let rejections =
+        process_iter(transactions, processor, &ProcessOptions::default())
+            .unwrap()
+            .rejections;
+    assert_eq!(rejections.len(), 1);
+    assert_eq!(rejections[0].line, 4);
+    assert_eq!(
+        rejections[0].row.iter().collect::<Vec<_>>(),
+        vec!["withdrawal", "2", "4", "5.0000", ""]
+    );
+    assert_eq!(std::fs::read_to_string(&tmp_out_file).unwrap(), expected);
+
+    std::fs::remove_file(tmp_out_file).unwrap();
+}
+
+#[test]
+fn test_borrowed_processor_spans_runs() {
+    let rnum: u32 = thread_rng().gen();
+    let tmp_out_file = format!("{}_{}", TMP_MEM_OUT_FILE, rnum);
+    let writer = std::fs::File::create(tmp_out_file.as_str()).unwrap();
+    let mut processor = MemoryProcessor::new(writer).unwrap();
+    let options = ProcessOptions::default();
+
+    let first = "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 4.0
+";
+    process_reader(first.as_bytes(), &mut processor, &options).unwrap();
+    assert_eq!(processor.get_client_record(1).unwrap().available, money("10.0"));
+
+    // The second run sees the transactions of the first
+    let second = "type, client, tx, amount
+dispute, 1, 1,
+withdrawal, 2, 3, 1.5
+";
+    process_reader(second.as_bytes(), &mut processor, &options).unwrap();
+    let cr = processor.get_client_record(1).unwrap();
+    assert_eq!((cr.available, cr.held), (Money::ZERO, money("10.0")));
+
+    // Nothing is written until the owner finishes
+    assert_eq!(std::fs::read_to_string(&tmp_out_file).unwrap(), "");
+    processor.finish().unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&tmp_out_file).unwrap(),
+        "client,available,held,total,locked
+1,0.0000,10.0000,10.0000,false
+2,2.5000,0.0000,2.5000,false
+"
+    );
+
+    std::fs::remove_file(tmp_out_file).unwrap();
+}
+
+#[test]
+fn test_lent_processor_keeps_its_overrides() {
+    let mut processor = CappedProcessor {
+        inner: MemoryProcessor::new(std::io::sink()).unwrap(),
+        cap: money("100.0"),
+    };
+    let input = "type, client, tx, amount
+deposit, 1, 1, 50.0
+deposit, 1, 2, 500.0
+";
+    let rejections =
+        process_reader(input.as_bytes(), &mut processor, &ProcessOptions::default())
+            .unwrap()
+            .rejections;
+
+    let lines: Vec<u64> = rejections.iter().map(|r| r.line).collect();
+    assert_eq!(lines, vec![3]);
+    assert_eq!(processor.get_client_record(1).unwrap().available, money("50.0"));
+}
+
+/// Refuses deposits above a cap, on top of the shared rules
+struct CappedProcessor {
+    inner: MemoryProcessor,
+    cap: Money,
+}
+
+impl Engine for CappedProcessor {
+    fn deposit_tx(&mut self, tx: Transaction) -> Result<Effect, KoalaError> {
+        let amount = tx.amount.unwrap_or_default();
+        if amount > self.cap {
+            return Err(KoalaError::AmountError {
+                client: tx.client,
+                tx: tx.tx,
+                amount,
+                expected: "at most the cap",
+            });
+        }
+        self.inner.deposit_tx(tx)
+    }
+
+    fn config(&self) -> &EngineConfig {
+        self.inner.config()
+    }
+
+    fn store_transaction(&mut self, tx: Transaction) -> Result<(), KoalaError> {
+        self.inner.store_transaction(tx)
+    }
+
+    fn find_original_transaction(
+        &self,
+        tx_id: &str,
+    ) -> Result<Option<Transaction>, KoalaError> {
+        self.inner.find_original_transaction(tx_id)
+    }
+
+    fn set_dispute_state(
+        &mut self,
+        tx_id: &str,
+        state: DisputeState,
+    ) -> Result<(), KoalaError> {
+        self.inner.set_dispute_state(tx_id, state)
+    }
+
+    fn get_client_record(
+        &self,
+        client_id: u16,
+    ) -> Result<ClientRecord, KoalaError> {
+        self.inner.get_client_record(client_id)
+    }
+
+    fn client_exists(&self, client_id: u16) -> Result<bool, KoalaError> {
+        self.inner.client_exists(client_id)
+    }
+
+    fn update_record(&mut self, cr: &ClientRecord) -> Result<(), KoalaError> {
+        self.inner.update_record(cr)
+    }
+}
+
+#[test]
+fn test_run_summary() {
+    let mut processor = MemoryProcessor::new(std::io::sink()).unwrap();
+    let options = ProcessOptions::default();
+
+    let first = "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+withdrawal, 1, 3, 2.5
+dispute, 2, 2,
+chargeback, 2, 2,
+withdrawal, 1, 4, 100.0
+deposit, nobody, 5, 1.0
+";
+    let summary =
+        process_reader(first.as_bytes(), &mut processor, &options).unwrap();
+    assert_eq!(summary.rows, 7);
+    assert_eq!(
+        summary.kinds,
+        BTreeMap::from([
+            ("chargeback", 1),
+            ("deposit", 2),
+            ("dispute", 1),
+            ("withdrawal", 2),
+        ])
+    );
+    assert_eq!(summary.accepted, 5);
+    assert_eq!(
+        summary.refused,
+        BTreeMap::from([("insufficient_funds", 1), ("malformed_row", 1)])
+    );
+    assert_eq!(summary.refused_total(), 2);
+    assert_eq!(summary.rejections.len(), 2);
+    assert_eq!(summary.deposited, money("15.0"));
+    assert_eq!(summary.withdrawn, money("2.5"));
+    assert_eq!(summary.held, money("5.0"));
+    assert_eq!(summary.charged_back, money("5.0"));
+    assert_eq!((summary.clients_created, summary.clients_locked), (2, 1));
+
+    // Clients of an earlier run are not created again
+    let second = "type, client, tx, amount
+deposit, 1, 6, 1.0
+deposit, 3, 7, 2.0
+";
+    let summary =
+        process_reader(second.as_bytes(), &mut processor, &options).unwrap();
+    assert_eq!((summary.rows, summary.accepted), (2, 2));
+    assert_eq!((summary.clients_created, summary.clients_locked), (1, 0));
+
+    // Replays are counted on their own and move no money
+    let config = EngineConfig {
+        duplicates: DuplicatePolicy::Replay,
+        ..EngineConfig::default()
+    };
+    let processor =
+        MemoryProcessor::new(std::io::sink()).unwrap().with_config(config);
+    let replayed = "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 4.0
+withdrawal, 1, 2, 4.0
+";
+    let summary =
+        process_reader(replayed.as_bytes(), processor, &options).unwrap();
+    assert_eq!(summary.rows, 4);
+    assert_eq!((summary.accepted, summary.replayed), (2, 2));
+    assert_eq!(summary.refused_total(), 0);
+    assert_eq!(summary.deposited, money("10.0"));
+    assert_eq!(summary.withdrawn, money("4.0"));
+}
+
+#[test]
+fn test_emptied_client_is_not_created_again() {
+    let rnum: u32 = thread_rng().gen();
+    let db_file = format!("{}_{}", TMP_DB, rnum);
+    let options = ProcessOptions::default();
+
+    // Client 1 withdraws everything in the first run, its record
+    // is kept with the database for the second
+    let runs = [
+        ("deposit, 1, 1, 5.0\nwithdrawal, 1, 2, 5.0\n", 1),
+        ("deposit, 1, 3, 2.0\ndeposit, 2, 4, 1.0\n", 1),
+    ];
+    for (rows, created) in runs {
+        let input = format!("type, client, tx, amount\n{}", rows);
+        let processor = DBProcessor::open(db_file.clone()).unwrap();
+        let summary =
+            process_reader(input.as_bytes(), processor, &options).unwrap();
+        assert_eq!(summary.clients_created, created);
+    }
+
+    remove_db(&db_file);
+}
+
+#[test]
+fn test_sharded_run_matches_sequential_run() {
+    let mut rng = thread_rng();
+    let rnum: u32 = rng.gen();
+    let input_file = format!("{}_{}", TMP_INPUT_FILE, rnum);
+    let tmp_out_file = format!("{}_{}", TMP_CSV_OUT_FILE, rnum);
+    generate_messy_input(&input_file, 5000);
+
+    // What a run leaves behind: the account table, the line and code
+    // of every refused row and the counts of the summary, or the row
+    // that stopped it
+    let summarize = |result: Result<RunSummary, KoalaError>| {
+        let outcome = match result {
+            Ok(summary) => Ok((
+                summary
+                    .rejections
+                    .iter()
+                    .map(|r| (r.line, r.reason.code()))
+                    .collect::<Vec<_>>(),
+                (
+                    summary.rows,
+                    summary.kinds,
+                    summary.accepted,
+                    summary.replayed,
+                    summary.refused,
+                ),
+                [
+                    summary.deposited,
+                    summary.withdrawn,
+                    summary.held,
+                    summary.charged_back,
+                ],
+                (summary.clients_created, summary.clients_locked),
+            )),
+            Err(KoalaError::AbortedError(rejection)) => {
+                Err((rejection.line, rejection.reason.code()))
+            }
+            Err(err) => panic!("unexpected error {}", err),
+        };
+        let output = std::fs::read_to_string(tmp_out_file.as_str()).unwrap();
+        (outcome, output)
+    };
+
+    for error_policy in
+        [ErrorPolicy::Skip, ErrorPolicy::Quarantine, ErrorPolicy::Abort]
+    {
+        for duplicates in [DuplicatePolicy::Reject, DuplicatePolicy::Replay] {
+            let config = EngineConfig {
+                duplicates,
+                withdrawal_disputes: WithdrawalDisputePolicy::ReverseAndHold,
+            };
+            let options = ProcessOptions {
+                error_policy,
+                ..ProcessOptions::default()
+            };
+
+            let writer = std::fs::File::create(tmp_out_file.as_str()).unwrap();
+            let processor =
+                MemoryProcessor::new(writer).unwrap().with_config(config.clone());
+            let expected =
+                summarize(process_txs(input_file.clone(), processor, &options));
+
+            for threads in [1, 2, 4, 7] {
+                let writer =
+                    std::fs::File::create(tmp_out_file.as_str()).unwrap();
+                let result = process_txs_sharded(
+                    input_file.clone(),
+                    writer,
+                    config.clone(),
+                    &options,
+                    threads,
+                );
+                assert_eq!(
+                    summarize(result),
+                    expected,
+                    "{:?} {:?} on {} threads",
+                    error_policy,
+                    duplicates,
+                    threads
+                );
+            }
+        }
+    }
+
+    std::fs::remove_file(input_file).unwrap();
+    std::fs::remove_file(tmp_out_file).unwrap();
+}
+
+/// Writes rows of every kind over few clients and tx ids, so ids are
+/// reused and referenced across clients and many rows are refused
+fn generate_messy_input(path: &str, rows: u32) {
+    let mut rng = StdRng::seed_from_u64(rows.into());
+    let mut wtr = csv::WriterBuilder::new()
+        .flexible(true)
+        .from_path(path)
+        .unwrap();
+    wtr.write_record(["type", "client", "tx", "amount", "reason"]).unwrap();
+
+    for _ in 0..rows {
+        let client = rng.gen_range(1..=12).to_string();
+        let tx = rng.gen_range(0..rows / 4).to_string();
+        let amount = Money::from_minor(rng.gen_range(-10_000..2_000_000));
+        let amount = amount.to_string();
+        let row = match rng.gen_range(0..20) {
+            0..=6 => ["deposit", &client, &tx, &amount, ""],
+            7..=10 => ["withdrawal", &client, &tx, &amount, ""],
+            11..=12 => ["dispute", &client, &tx, "", ""],
+            13 => ["resolve", &client, &tx, "", ""],
+            14 => ["chargeback", &client, &tx, "", ""],
+            15 => ["freeze", &client, &tx, "", ""],
+            16 => ["unlock", &client, &tx, "", ""],
+            17 => ["adjustment", &client, &tx, &amount, "correction"],
+            18 => ["deposit", &client, &tx, "", ""],
+            _ => ["deposit", "nobody", &tx, &amount, ""],
+        };
+        wtr.write_record(row).unwrap();
+    }
+    wtr.flush().unwrap();
+}
+
+#[test]
+fn test_replay_reads_generated_tables() {
+    let rnum: u32 = thread_rng().gen();
+    let input_file = format!("{}_{}", TMP_INPUT_FILE, rnum);
+    let expected_file = format!("{}_{}", TMP_CSV_OUT_FILE, rnum);
+    let tmp_file = format!("{}_{}", TMP_CSV_FILE, rnum);
+
+    let input = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+dispute,2,2,
+chargeback,2,2,
+";
+    std::fs::write(input_file.as_str(), input).unwrap();
+
+    let replay = |expected: &str| {
+        std::fs::write(expected_file.as_str(), expected).unwrap();
+        std::process::Command::new(env!("CARGO_BIN_EXE_koala"))
+            .args(["replay", "--input", &input_file, "--expected", &expected_file])
+            .env("KOALA_TMP_CSV_FILE", &tmp_file)
+            .output()
+            .unwrap()
+            .status
+            .code()
+    };
+
+    // utils/tx_gen.py writes the locked flag as Python does
+    let generated = "client,available,held,total,locked
+1,10.0000,0.0000,10.0000,False
+2,0.0000,0.0000,0.0000,True
+";
+    assert_eq!(replay(generated), Some(0));
+    assert_eq!(replay(&generated.replace("True", "False")), Some(3));
+
+    std::fs::remove_file(input_file).unwrap();
+    std::fs::remove_file(expected_file).unwrap();
+}
+
+#[test]
+fn test_aborted_run_reports_its_row() {
+    let rnum: u32 = thread_rng().gen();
+    let input_file = format!("{}_{}", TMP_INPUT_FILE, rnum);
+    let rejects_file = format!("{}_{}", TMP_CSV_OUT_FILE, rnum);
+    let input = "type,client,tx,amount
+deposit,1,1,2.0
+withdrawal,1,2,5.0
+deposit,1,3,1.0
+";
+    std::fs::write(input_file.as_str(), input).unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_koala"))
+        .args(["process", "--backend", "memory", "--on-error", "abort"])
+        .args(["--input", &input_file, "--output", "-"])
+        .args(["--rejects", &rejects_file])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let report = std::fs::read_to_string(rejects_file.as_str()).unwrap();
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[1].starts_with(",3,1,2,insufficient_funds,"));
+    assert!(lines[1].ends_with(",\"withdrawal,1,2,5.0\""));
+
+    std::fs::remove_file(input_file).unwrap();
+    std::fs::remove_file(rejects_file).unwrap();
+}
+
+#[test]
+fn test_db_backend_needs_no_output_file() {
+    let rnum: u32 = thread_rng().gen();
+    let dir = std::env::current_dir().unwrap();
+    let input_file = dir.join(format!("{}_{}", TMP_INPUT_FILE, rnum));
+    let db_file = dir.join(format!("{}_{}", TMP_DB, rnum));
+    std::fs::write(&input_file, "type,client,tx,amount\ndeposit,1,1,2.0\n")
+        .unwrap();
+
+    // No config file and no --output, which only the csv
+    // and memory backends write
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_koala"))
+        .args(["process", "--backend", "db", "--input"])
+        .arg(&input_file)
+        .arg("--db-file")
+        .arg(&db_file)
+        .current_dir(dir.join("data/tmp"))
+        .env_remove("KOALA_OUTPUT_FILE")
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(0));
+
+    std::fs::remove_file(input_file).unwrap();
+    std::fs::remove_file(db_file).unwrap();
+}
+
+#[test]
+fn test_admin_transactions_need_an_operator() {
+    let mut rng = thread_rng();
+    let rnum: u32 = rng.gen();
+    let input_file = format!("{}_{}", TMP_INPUT_FILE, rnum);
+    let tmp_out_file = format!("{}_{}", TMP_CSV_OUT_FILE, rnum);
+
+    let input = "type, client, tx, amount, reason
+deposit, 1, 1, 10.0,
+dispute, 1, 1, ,
+chargeback, 1, 1, ,
+unlock, 1, 2, , cleared by support
+adjustment, 1, 3, 2.5, goodwill credit
+deposit, 1, 4, 1.0,
+";
+    std::fs::write(input_file.as_str(), input).unwrap();
+
+    // Customer traffic can't unlock or adjust the account
+    let writer = std::fs::File::create(tmp_out_file.as_str()).unwrap();
+    let processor = MemoryProcessor::new(writer).unwrap();
+    let rejections =
+        process_txs(input_file.clone(), processor, &ProcessOptions::default())
+            .unwrap()
+            .rejections;
+    let codes: Vec<&str> = rejections.iter().map(|r| r.reason.code()).collect();
+    assert_eq!(codes, vec!["unauthorized", "unauthorized", "account_locked"]);
+    assert_eq!(
+        std::fs::read_to_string(tmp_out_file.as_str()).unwrap(),
+        "client,available,held,total,locked
+1,0.0000,0.0000,0.0000,true
+"
+    );
+
+    // An operator can, while a customer stream read
+    // along with it still can't
+    let options = ProcessOptions::default();
+    let writer = std::fs::File::create(tmp_out_file.as_str()).unwrap();
+    let mut processor = MemoryProcessor::new(writer).unwrap();
+    let locking = "type, client, tx, amount
+deposit, 1, 1, 10.0
+dispute, 1, 1,
+chargeback, 1, 1,
+";
+    process_reader(locking.as_bytes(), &mut processor, &options).unwrap();
+
+    let customer = "type, client, tx, amount, reason
+adjustment, 1, 5, 100.0, self service
+";
+    let support = "type, client, tx, amount, reason
+unlock, 1, 2, , cleared by support
+adjustment, 1, 3, 2.5, goodwill credit
+";
+    let sources = vec![
+        Source::new("customer", customer.as_bytes()),
+        Source::new("support", support.as_bytes()).operator(),
+    ];
+    let rejections = process_sources(sources, &mut processor, &options)
+        .unwrap()
+        .rejections;
+    assert_eq!(rejections.len(), 1);
+    assert_eq!(rejections[0].source.as_deref(), Some("customer"));
+    assert_eq!(rejections[0].reason.code(), "unauthorized");
+
+    let deposit = "type, client, tx, amount
+deposit, 1, 4, 1.0
+";
+    process_reader(deposit.as_bytes(), &mut processor, &options).unwrap();
+    processor.finish().unwrap();
+    assert_eq!(
+        std::fs::read_to_string(tmp_out_file.as_str()).unwrap(),
+        "client,available,held,total,locked
+1,3.5000,0.0000,3.5000,false
+"
+    );
+
+    std::fs::remove_file(input_file).unwrap();
+    std::fs::remove_file(tmp_out_file).unwrap();
+}
+
+/// Writes a random but valid input file, returning the
+/// expected (available, held) balance of every client
+fn generate_input(path: &str, rows: u32) -> BTreeMap<u16, (Money, Money)> {
+    let mut rng = StdRng::seed_from_u64(rows.into());
+    let mut wtr = csv::Writer::from_path(path).unwrap();
+    wtr.write_record(["type", "client", "tx", "amount"]).unwrap();
+
+    let mut balances: BTreeMap<u16, (Money, Money)> = BTreeMap::new();
+    let mut deposits: Vec<(u16, String, Money)> = Vec::new();
+    let mut disputed: Vec<(u16, String, Money)> = Vec::new();
+
+    for tx_id in 0..rows {
+        let client: u16 = rng.gen_range(1..=20);
+        let tx = tx_id.to_string();
+        let disputable = deposits
+            .iter()
+            .position(|(c, _, amount)| balances[c].0 >= *amount);
+        let (available, _) = balances.entry(client).or_default();
+
+        match (rng.gen_range(0..10), disputable) {
+            (5..=7, _) => {
+                let amount =
+                    Money::from_minor(rng.gen_range(0..=available.minor()));
+                *available = available.checked_sub(amount).unwrap();
+                let row = ["withdrawal", &client.to_string(), &tx, &amount.to_string()];
+                wtr.write_record(row).unwrap();
+            }
+            (8, Some(index)) => {
+                let (client, tx, amount) = deposits.swap_remove(index);
+                let (available, held) = balances.get_mut(&client).unwrap();
+                *available = available.checked_sub(amount).unwrap();
+                *held = held.checked_add(amount).unwrap();
+                let row = ["dispute", &client.to_string(), &tx, ""];
+                wtr.write_record(row).unwrap();
+                disputed.push((client, tx, amount));
+            }
+            (9, _) if !disputed.is_empty() => {
+                let (client, tx, amount) = disputed.pop().unwrap();
+                let (available, held) = balances.get_mut(&client).unwrap();
+                *held = held.checked_sub(amount).unwrap();
+                *available = available.checked_add(amount).unwrap();
+                let row = ["resolve", &client.to_string(), &tx, ""];
+                wtr.write_record(row).unwrap();
+            }
+            _ => {
+                let amount =
+                    Money::from_minor(rng.gen_range(1..500_000_000));
+                *available = available.checked_add(amount).unwrap();
+                let row = ["deposit", &client.to_string(), &tx, &amount.to_string()];
+                wtr.write_record(row).unwrap();
+                deposits.push((client, tx, amount));
+            }
+        }
+    }
+    wtr.flush().unwrap();
+
+    balances
+}
+
+#[test]
+fn test_memory_method() {
+    let mut processor = MemoryProcessor::new(std::io::sink()).unwrap();
+
+    // Run test
+    transact_and_verify(&mut processor);
+}
+
+#[test]
+fn test_dispute_state_machine() {
+    for_each_processor(dispute_state_machine);
+}
+
+#[test]
+fn test_dispute_requires_same_client() {
+    for_each_processor(dispute_other_clients_transaction);
+}
+
+#[test]
+fn test_duplicate_transactions() {
+    for_each_processor(duplicates_rejected);
+
+    let config = EngineConfig {
+        duplicates: DuplicatePolicy::Replay,
+        ..EngineConfig::default()
+    };
+    for_each_processor_with(config, duplicates_replayed);
+}
+
+#[test]
+fn test_admin_transactions() {
+    for_each_processor(admin_transactions);
+}
+
+#[test]
+fn test_amount_rules() {
+    for_each_processor(amount_rules);
+}
+
+#[test]
+fn test_withdrawal_disputes() {
+    for_each_processor(withdrawal_disputes_rejected);
+
+    let config = EngineConfig {
+        withdrawal_disputes: WithdrawalDisputePolicy::ReverseAndHold,
+        ..EngineConfig::default()
+    };
+    for_each_processor_with(config, withdrawal_disputes_reversed);
+}
+
+/// Runs the scenario against a fresh processor of every kind
+fn for_each_processor(scenario: fn(&mut dyn Engine)) {
+    for_each_processor_with(EngineConfig::default(), scenario);
+}
+
+/// Runs the scenario against a fresh processor of every
+/// kind, each following the given config
+fn for_each_processor_with(
+    config: EngineConfig,
+    scenario: fn(&mut dyn Engine),
+) {
+    let mut rng = thread_rng();
+    let rnum: u32 = rng.gen();
+    let tmp_file = format!("{}_{}", TMP_CSV_FILE, rnum);
+    let tmp_out_file = format!("{}_{}", TMP_CSV_OUT_FILE, rnum);
+    let db_file = format!("{}_{}", TMP_DB, rnum);
+
+    std::fs::File::create(tmp_file.as_str()).unwrap();
+    std::fs::File::create(tmp_out_file.as_str()).unwrap();
+    let mut processor =
+        CSVProcessor::new(tmp_file.clone(), tmp_out_file.clone())
+            .unwrap()
+            .with_config(config.clone());
+    scenario(&mut processor);
+
+    std::fs::File::create(db_file.as_str()).unwrap();
+    let mut processor = DBProcessor::new(db_file.clone())
+        .unwrap()
+        .with_config(config.clone());
+    scenario(&mut processor);
+
+    let mut processor = MemoryProcessor::new(std::io::sink())
+        .unwrap()
+        .with_config(config);
+    scenario(&mut processor);
+
+    // Cleanup
+    remove_db(&db_file);
+    for file in [tmp_file, tmp_out_file] {
+        if std::path::Path::new(&file).exists() {
+            println!("Cleaning {}", file);
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+}
+
+fn dispute_other_clients_transaction(processor: &mut dyn Engine) {
+    deposit(processor, 9, "1".to_string(), "100.0");
+    deposit(processor, 5, "2".to_string(), "1.0");
+
+    // Client 5 refers to the deposit of client 9
+    for tx_type in ["dispute", "resolve", "chargeback"] {
+        let tx = Transaction::new(tx_type.parse().unwrap(), 5, "1".to_string(), None);
+        let result = match tx_type {
+            "dispute" => processor.dispute_tx(&tx),
+            "resolve" => processor.resolve_tx(&tx),
+            _ => processor.chargeback_tx(&tx),
+        };
+        assert!(matches!(result, Err(KoalaError::ClientMismatchError { .. })));
+    }
+
+    // Neither account moved
+    let cr = processor.get_client_record(5).unwrap();
+    assert_eq!((cr.available, cr.held), (money("1.0"), Money::ZERO));
+    assert!(!cr.locked);
+    let cr = processor.get_client_record(9).unwrap();
+    assert_eq!((cr.available, cr.held), (money("100.0"), Money::ZERO));
+
+    // The owner can still dispute it
+    dispute(processor, 9, "1".to_string());
+    let cr = processor.get_client_record(9).unwrap();
+    assert_eq!((cr.available, cr.held), (Money::ZERO, money("100.0")));
+}
+
+fn duplicates_rejected(processor: &mut dyn Engine) {
+    deposit(processor, 1, "1".to_string(), "10.0");
+
+    // Any reuse of the id is refused, even an exact repeat
+    let repeats = [
+        ("deposit", 1, "10.0"),
+        ("deposit", 1, "5.0"),
+        ("withdrawal", 1, "1.0"),
+        ("deposit", 2, "10.0"),
+    ];
+    for (tx_type, client_id, amount) in repeats {
+        let tx = Transaction::new(
+            tx_type.parse().unwrap(),
+            client_id,
+            "1".to_string(),
+            Some(money(amount)),
+        );
+        let result = match tx_type {
+            "deposit" => processor.deposit_tx(tx),
+            _ => processor.withdrawal_tx(tx),
+        };
+        assert!(matches!(result, Err(KoalaError::DuplicateTransactionError { .. })));
     }
+
+    let cr = processor.get_client_record(1).unwrap();
+    assert_eq!(cr.available, money("10.0"));
+    let cr = processor.get_client_record(2).unwrap();
+    assert_eq!(cr.available, Money::ZERO);
+
+    // A refused transaction is not kept, so its id can be used again
+    let tx = Transaction::new(
+        TransactionKind::Withdrawal,
+        1,
+        "2".to_string(),
+        Some(money("20.0")),
+    );
+    assert!(matches!(
+        processor.withdrawal_tx(tx),
+        Err(KoalaError::BalanceError { .. })
+    ));
+    withdraw(processor, 1, "2".to_string(), "4.0");
+    let cr = processor.get_client_record(1).unwrap();
+    assert_eq!(cr.available, money("6.0"));
+}
+
+fn duplicates_replayed(processor: &mut dyn Engine) {
+    deposit(processor, 1, "1".to_string(), "10.0");
+    withdraw(processor, 1, "2".to_string(), "4.0");
+
+    // Exact repeats are ignored
+    deposit(processor, 1, "1".to_string(), "10.0");
+    withdraw(processor, 1, "2".to_string(), "4.0");
+    let cr = processor.get_client_record(1).unwrap();
+    assert_eq!(cr.available, money("6.0"));
+
+    // A repeat which differs is still refused
+    let tx = Transaction::new(
+        TransactionKind::Deposit,
+        1,
+        "1".to_string(),
+        Some(money("11.0")),
+    );
+    assert!(matches!(
+        processor.deposit_tx(tx),
+        Err(KoalaError::DuplicateTransactionError { .. })
+    ));
+    let cr = processor.get_client_record(1).unwrap();
+    assert_eq!(cr.available, money("6.0"));
+}
+
+fn admin_transactions(processor: &mut dyn Engine) {
+    let client_id = 1;
+    deposit(processor, client_id, "1".to_string(), "10.0");
+    let admin_tx = |tx_type: &str, tx_id: &str, amount: Option<&str>| {
+        let mut tx = Transaction::new(
+            tx_type.parse().unwrap(),
+            client_id,
+            tx_id.to_string(),
+            amount.map(money),
+        );
+        tx.reason = Some("support ticket".to_string());
+        tx
+    };
+
+    // A frozen account refuses customer traffic
+    processor.freeze_tx(admin_tx("freeze", "2", None)).unwrap();
+    assert!(processor.get_client_record(client_id).unwrap().locked);
+    let tx = Transaction::new(
+        TransactionKind::Deposit,
+        client_id,
+        "3".to_string(),
+        Some(money("1.0")),
+    );
+    assert!(matches!(
+        processor.deposit_tx(tx),
+        Err(KoalaError::AccountLockedError { .. })
+    ));
+
+    // Adjustments apply to locked accounts, but not below zero
+    processor
+        .adjustment_tx(admin_tx("adjustment", "4", Some("-2.5")))
+        .unwrap();
+    assert!(matches!(
+        processor.adjustment_tx(admin_tx("adjustment", "5", Some("-8.0"))),
+        Err(KoalaError::BalanceError { .. })
+    ));
+    let mut tx = admin_tx("adjustment", "5", Some("1.0"));
+    tx.reason = None;
+    assert!(matches!(
+        processor.adjustment_tx(tx),
+        Err(KoalaError::ReasonMissingError { .. })
+    ));
+
+    processor.unlock_tx(admin_tx("unlock", "6", None)).unwrap();
+    withdraw(processor, client_id, "7".to_string(), "7.5");
+    let cr = processor.get_client_record(client_id).unwrap();
+    assert_eq!((cr.available, cr.total), (Money::ZERO, Money::ZERO));
+    assert!(!cr.locked);
+
+    // Every administrative transaction is kept in the history,
+    // but can't be disputed
+    let kept = processor.find_original_transaction("4").unwrap().unwrap();
+    assert_eq!(kept.tx_type, TransactionKind::Adjustment);
+    assert_eq!(kept.amount, Some(money("-2.5")));
+    assert_eq!(kept.reason.as_deref(), Some("support ticket"));
+    for tx_id in ["2", "6"] {
+        let kept = processor.find_original_transaction(tx_id).unwrap();
+        assert!(kept.is_some());
+    }
+    let tx = Transaction::new(TransactionKind::Dispute, 1, "4".to_string(), None);
+    assert!(matches!(
+        processor.dispute_tx(&tx),
+        Err(KoalaError::NotDisputableError { .. })
+    ));
+}
+
+fn withdrawal_disputes_rejected(processor: &mut dyn Engine) {
+    deposit(processor, 1, "1".to_string(), "10.0");
+    withdraw(processor, 1, "2".to_string(), "4.0");
+
+    let tx = Transaction::new(TransactionKind::Dispute, 1, "2".to_string(), None);
+    assert!(matches!(
+        processor.dispute_tx(&tx),
+        Err(KoalaError::WithdrawalDisputeError { .. })
+    ));
+
+    let cr = processor.get_client_record(1).unwrap();
+    assert_eq!((cr.available, cr.held), (money("6.0"), Money::ZERO));
+    assert_eq!(cr.total, money("6.0"));
+}
+
+fn withdrawal_disputes_reversed(processor: &mut dyn Engine) {
+    let client_id = 1;
+    deposit(processor, client_id, "1".to_string(), "10.0");
+    withdraw(processor, client_id, "2".to_string(), "4.0");
+    let balances = |processor: &mut dyn Engine| {
+        let cr = processor.get_client_record(client_id).unwrap();
+        (cr.available, cr.held, cr.total)
+    };
+
+    // The withdrawn funds come back, but are held
+    dispute(processor, client_id, "2".to_string());
+    assert_eq!(
+        balances(processor),
+        (money("6.0"), money("4.0"), money("10.0"))
+    );
+
+    // Resolving lets the withdrawal stand
+    resolve(processor, client_id, "2".to_string());
+    assert_eq!(
+        balances(processor),
+        (money("6.0"), Money::ZERO, money("6.0"))
+    );
+
+    // Charging back another one reverses it and locks the account
+    withdraw(processor, client_id, "4".to_string(), "2.0");
+    dispute(processor, client_id, "4".to_string());
+    chargeback(processor, client_id, "4".to_string());
+    assert_eq!(
+        balances(processor),
+        (money("6.0"), Money::ZERO, money("6.0"))
+    );
+    assert!(processor.get_client_record(client_id).unwrap().locked);
+
+    // Deposits are disputed as before
+    deposit(processor, 2, "3".to_string(), "5.0");
+    dispute(processor, 2, "3".to_string());
+    let cr = processor.get_client_record(2).unwrap();
+    assert_eq!((cr.available, cr.held), (Money::ZERO, money("5.0")));
+}
+
+fn amount_rules(processor: &mut dyn Engine) {
+    deposit(processor, 1, "1".to_string(), "10.0");
+    let tx = |tx_type: &str, tx_id: &str, amount: Option<&str>| {
+        Transaction::new(tx_type.parse().unwrap(), 1, tx_id.into(), amount.map(money))
+    };
+
+    // Called directly, the kind's amount rule comes first
+    assert!(matches!(
+        processor.deposit_tx(tx("deposit", "2", None)),
+        Err(KoalaError::AmountMissingError { .. })
+    ));
+    assert!(matches!(
+        processor.withdrawal_tx(tx("withdrawal", "3", None)),
+        Err(KoalaError::AmountMissingError { .. })
+    ));
+    assert!(matches!(
+        processor.dispute_tx(&tx("dispute", "1", Some("10.0"))),
+        Err(KoalaError::AmountNotAllowedError { .. })
+    ));
+    assert!(matches!(
+        processor.freeze_tx(tx("freeze", "4", Some("1.0"))),
+        Err(KoalaError::AmountNotAllowedError { .. })
+    ));
+
+    let cr = processor.get_client_record(1).unwrap();
+    assert_eq!((cr.available, cr.held), (money("10.0"), Money::ZERO));
+    assert!(!cr.locked);
+}
+
+fn dispute_state_machine(processor: &mut dyn Engine) {
+    let client_id = 1;
+    let tx_id = "1".to_string();
+    deposit(processor, client_id, tx_id.clone(), "10.0");
+
+    let tx = |tx_type: &str| {
+        Transaction::new(tx_type.parse().unwrap(), client_id, tx_id.clone(), None)
+    };
+    let state = |processor: &mut dyn Engine| {
+        processor.find_original_transaction("1").unwrap().unwrap().state
+    };
+
+    // Nothing to resolve or charge back before a dispute
+    assert!(matches!(
+        processor.resolve_tx(&tx("resolve")),
+        Err(KoalaError::DisputeStateError { .. })
+    ));
+    assert!(matches!(
+        processor.chargeback_tx(&tx("chargeback")),
+        Err(KoalaError::DisputeStateError { .. })
+    ));
+    assert_eq!(state(processor), DisputeState::Normal);
+
+    // A second dispute must not hold the amount twice
+    processor.dispute_tx(&tx("dispute")).unwrap();
+    assert!(matches!(
+        processor.dispute_tx(&tx("dispute")),
+        Err(KoalaError::DisputeStateError { .. })
+    ));
+    let cr = processor.get_client_record(client_id).unwrap();
+    assert_eq!(cr.available, Money::ZERO);
+    assert_eq!(cr.held, money("10.0"));
+    assert_eq!(state(processor), DisputeState::Disputed);
+
+    // Resolve once
+    processor.resolve_tx(&tx("resolve")).unwrap();
+    assert!(matches!(
+        processor.resolve_tx(&tx("resolve")),
+        Err(KoalaError::DisputeStateError { .. })
+    ));
+    let cr = processor.get_client_record(client_id).unwrap();
+    assert_eq!(cr.available, money("10.0"));
+    assert_eq!(cr.held, Money::ZERO);
+    assert_eq!(state(processor), DisputeState::Resolved);
+
+    // A resolved transaction stays resolved
+    assert!(matches!(
+        processor.dispute_tx(&tx("dispute")),
+        Err(KoalaError::DisputeStateError { .. })
+    ));
+    let cr = processor.get_client_record(client_id).unwrap();
+    assert_eq!(cr.held, Money::ZERO);
+    assert_eq!(state(processor), DisputeState::Resolved);
+
+    // Another deposit goes on to be charged back
+    deposit(processor, client_id, "2".to_string(), "4.0");
+    let other = |tx_type: &str| {
+        Transaction::new(tx_type.parse().unwrap(), client_id, "2".into(), None)
+    };
+    processor.dispute_tx(&other("dispute")).unwrap();
+    processor.chargeback_tx(&other("chargeback")).unwrap();
+    let cr = processor.get_client_record(client_id).unwrap();
+    assert_eq!(cr.total, money("10.0"));
+    assert!(cr.locked);
 }
 
 fn transact_and_verify(processor: &mut impl Engine) {
@@ -64,56 +1590,56 @@ fn transact_and_verify(processor: &mut impl Engine) {
     // Test deposit and withdrawal
     let client_id = 1;
     let tx_id = "1".to_string();
-    let amount = 10.0;
+    let amount = "10.0";
     deposit(processor, client_id, tx_id.clone(), amount);
 
     let tx_id = "2".to_string();
-    let amount = 5.0;
+    let amount = "5.0";
     deposit(processor, client_id, tx_id.clone(), amount);
 
     let tx_id = "3".to_string();
-    let amount = 3.0;
+    let amount = "3.0";
     withdraw(processor, client_id, tx_id.clone(), amount);
 
     // Verify
     let cr = processor.get_client_record(client_id).unwrap();
-    assert_eq!(cr.available, 12.0);
-    assert_eq!(cr.total, 12.0);
-    assert_eq!(cr.held, 0.0);
-    assert_eq!(cr.locked, false);
+    assert_eq!(cr.available, money("12.0"));
+    assert_eq!(cr.total, money("12.0"));
+    assert_eq!(cr.held, Money::ZERO);
+    assert!(!cr.locked);
 
     // Test dispute and resolve
     let client_id = 2;
     let tx_id = "4".to_string();
-    let amount = 5.0;
+    let amount = "5.0";
     deposit(processor, client_id, tx_id.clone(), amount);
 
     let tx_id = "5".to_string();
-    let amount = 50000.0;
+    let amount = "50000.0";
     deposit(processor, client_id, tx_id.clone(), amount);
 
     dispute(processor, client_id, tx_id.clone());
 
     // Verify dispute
     let cr = processor.get_client_record(client_id).unwrap();
-    assert_eq!(cr.available, 5.0);
-    assert_eq!(cr.total, 50005.0);
-    assert_eq!(cr.held, 50000.0);
-    assert_eq!(cr.locked, false);
+    assert_eq!(cr.available, money("5.0"));
+    assert_eq!(cr.total, money("50005.0"));
+    assert_eq!(cr.held, money("50000.0"));
+    assert!(!cr.locked);
 
     resolve(processor, client_id, tx_id);
 
     // Verify resolve
     let cr = processor.get_client_record(client_id).unwrap();
-    assert_eq!(cr.available, 50005.0);
-    assert_eq!(cr.total, 50005.0);
-    assert_eq!(cr.held, 0.0);
-    assert_eq!(cr.locked, false);
+    assert_eq!(cr.available, money("50005.0"));
+    assert_eq!(cr.total, money("50005.0"));
+    assert_eq!(cr.held, Money::ZERO);
+    assert!(!cr.locked);
 
     // Test chargeback
     let client_id = 3;
     let tx_id = "6".to_string();
-    let amount = 5000000.0;
+    let amount = "5000000.0";
     deposit(processor, client_id, tx_id.clone(), amount);
 
     dispute(processor, client_id, tx_id.clone());
@@ -122,48 +1648,52 @@ fn transact_and_verify(processor: &mut impl Engine) {
 
     // Verify chargeback
     let cr = processor.get_client_record(client_id).unwrap();
-    assert_eq!(cr.available, 0.0);
-    assert_eq!(cr.total, 0.0);
-    assert_eq!(cr.held, 0.0);
-    assert_eq!(cr.locked, true);
+    assert_eq!(cr.available, Money::ZERO);
+    assert_eq!(cr.total, Money::ZERO);
+    assert_eq!(cr.held, Money::ZERO);
+    assert!(cr.locked);
 }
 
 fn deposit(
-    processor: &mut impl Engine,
+    processor: &mut dyn Engine,
     client_id: u16,
     tx_id: String,
-    amount: f32,
+    amount: &str,
 ) {
-    let tx_type = String::from("deposit");
-    let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
+    let tx_type = TransactionKind::Deposit;
+    let tx = Transaction::new(tx_type, client_id, tx_id, Some(money(amount)));
     processor.deposit_tx(tx).unwrap();
 }
 
 fn withdraw(
-    processor: &mut impl Engine,
+    processor: &mut dyn Engine,
     client_id: u16,
     tx_id: String,
-    amount: f32,
+    amount: &str,
 ) {
-    let tx_type = String::from("withdrawal");
-    let tx = Transaction::new(tx_type, client_id, tx_id, Some(amount));
+    let tx_type = TransactionKind::Withdrawal;
+    let tx = Transaction::new(tx_type, client_id, tx_id, Some(money(amount)));
     processor.withdrawal_tx(tx).unwrap();
 }
 
-fn dispute(processor: &mut impl Engine, client_id: u16, tx_id: String) {
-    let tx_type = String::from("dispute");
+fn dispute(processor: &mut dyn Engine, client_id: u16, tx_id: String) {
+    let tx_type = TransactionKind::Dispute;
     let tx = Transaction::new(tx_type, client_id, tx_id, None);
     processor.dispute_tx(&tx).unwrap();
 }
 
-fn resolve(processor: &mut impl Engine, client_id: u16, tx_id: String) {
-    let tx_type = String::from("resolve");
+fn resolve(processor: &mut dyn Engine, client_id: u16, tx_id: String) {
+    let tx_type = TransactionKind::Resolve;
     let tx = Transaction::new(tx_type, client_id, tx_id, None);
     processor.resolve_tx(&tx).unwrap();
 }
 
-fn chargeback(processor: &mut impl Engine, client_id: u16, tx_id: String) {
-    let tx_type = String::from("chargeback");
+fn chargeback(processor: &mut dyn Engine, client_id: u16, tx_id: String) {
+    let tx_type = TransactionKind::Chargeback;
     let tx = Transaction::new(tx_type, client_id, tx_id, None);
     processor.chargeback_tx(&tx).unwrap();
 }
+
+fn money(amount: &str) -> Money {
+    amount.parse().unwrap()
+}
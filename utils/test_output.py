@@ -15,6 +15,8 @@ TEST_DATA_FILE = config["output_test_file"]
 OUTPUT_FILE = config["output_file"]
 DB_FILE = config["tmp_db_file"]
 DB_NAME = "CLIENT_RECORDS"
+# Amounts are stored in the db as integer minor units
+DB_SCALE = 10_000
 
 
 def assert_data(a, b):
@@ -69,14 +71,15 @@ def test_db_output():
             row = cursor.fetchone()
 
             print("\n===================")
-            row_info = f"'client': '{row[0]}', 'available': '{row[1]}', 'held': '{row[2]}', 'total': '{row[3]}', 'locked': '{row[4]}'"
+            available, held, total = (value / DB_SCALE for value in row[1:4])
+            row_info = f"'client': '{row[0]}', 'available': '{available}', 'held': '{held}', 'total': '{total}', 'locked': '{row[4]}'"
             print(f"{{{row_info}}}")
             print(test_row)
             print("======================")
 
-            assert_data(test_row["available"], row[1])
-            assert_data(test_row["held"], row[2])
-            assert_data(test_row["total"], row[3])
+            assert_data(test_row["available"], available)
+            assert_data(test_row["held"], held)
+            assert_data(test_row["total"], total)
 
             locked = None
             if row[4] == 1:
@@ -17,7 +17,10 @@ TX_TO_GENERATE = 100
 MAX_AMOUNT = 50000
 APPROX_TOTAL_CLIENTS = 2000  # Auto incremented if accounts are locked
 ROUND_DIGITS = 2
+OUTPUT_DIGITS = 4  # Matches the engine's fixed-point precision
 TXS = []
+# A deposit can only be disputed once, resolved and charged back are final
+DISPUTED_TXS = set()
 CLIENT_DICT = {}
 TX_COUNT = 0
 
@@ -140,6 +143,7 @@ def dispute(writer):
 
     # Create the transaction
     writer.writerow(["dispute", client_id, tx_id, None])
+    DISPUTED_TXS.add(tx_id)
 
     available = CLIENT_DICT[client_id]["available"]
     new_available = available - amount
@@ -229,7 +233,12 @@ def get_tx_data(client_id, available):
     amount = None
     tx_id = None
     for i in TXS:
-        if i[1] == client_id and i[3] == "dep" and available > i[2]:
+        if (
+            i[1] == client_id
+            and i[3] == "dep"
+            and available > i[2]
+            and i[0] not in DISPUTED_TXS
+        ):
             amount = i[2]
             tx_id = i[0]
 
@@ -239,7 +248,12 @@ def get_tx_data(client_id, available):
 def get_held_tx_data(client_id, held):
     tx_id = None
     for i in TXS:
-        if i[1] == client_id and i[3] == "dep" and held == i[2]:
+        if (
+            i[1] == client_id
+            and i[3] == "dep"
+            and held == i[2]
+            and i[0] in DISPUTED_TXS
+        ):
             tx_id = i[0]
 
     return tx_id
@@ -317,9 +331,9 @@ def main(number_of_txs):
                 writer.writerow(
                     [
                         client,
-                        CLIENT_DICT[client]["available"],
-                        CLIENT_DICT[client]["held"],
-                        CLIENT_DICT[client]["total"],
+                        f"{available:.{OUTPUT_DIGITS}f}",
+                        f"{held:.{OUTPUT_DIGITS}f}",
+                        f"{total:.{OUTPUT_DIGITS}f}",
                         CLIENT_DICT[client]["locked"],
                     ]
                 )